@@ -0,0 +1,69 @@
+use sqlx::FromRow;
+use tracing::Instrument;
+
+use crate::error::Result;
+use crate::telemetry;
+use crate::AliasRow;
+use crate::ServerState;
+use crate::UrlRow;
+
+/// A link and the alias it was found by, spelled the way the alias is stored.
+#[derive(Debug, FromRow)]
+pub struct Found {
+    pub alias: String,
+    #[sqlx(flatten)]
+    pub link: UrlRow,
+}
+
+/// The link that `id` is an alias of. Every link has at least one alias, the id it was
+/// created with, which is also its `UrlRow::id`. With case-insensitive ids, `id` matches
+/// aliases that only differ in case.
+pub async fn find_link(state: &ServerState, domain: &str, id: &str) -> Result<Option<Found>> {
+    Ok(sqlx::query_as(&format!(
+        "
+SELECT aliases.id AS alias, urls.*
+FROM chela.aliases
+JOIN chela.urls ON urls.index = aliases.link
+WHERE aliases.domain = $1 AND {}
+        ",
+        state.id_rules.sql_match("aliases.id", "$2")
+    ))
+    .bind(domain)
+    .bind(id)
+    .fetch_optional(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.aliases"))
+    .await?)
+}
+
+/// Every alias of a link, the one it was created with first.
+pub async fn list(state: &ServerState, link: &UrlRow) -> Result<Vec<AliasRow>> {
+    Ok(sqlx::query_as(
+        "
+SELECT * FROM chela.aliases
+WHERE link = $1
+ORDER BY id <> $2, created_at, id
+        ",
+    )
+    .bind(link.index)
+    .bind(&link.id)
+    .fetch_all(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.aliases"))
+    .await?)
+}
+
+/// Visits to a link through any of its aliases.
+pub async fn clicks(state: &ServerState, link: &UrlRow) -> Result<i64> {
+    let (clicks,): (i64,) = sqlx::query_as(
+        "
+SELECT count(*)
+FROM chela.tracking
+JOIN chela.aliases ON aliases.domain = tracking.domain AND aliases.id = tracking.id
+WHERE aliases.link = $1
+        ",
+    )
+    .bind(link.index)
+    .fetch_one(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.tracking"))
+    .await?;
+    Ok(clicks)
+}
@@ -0,0 +1,327 @@
+use std::env;
+
+use sqlx::{Pool, Postgres};
+use tracing::info;
+use url::Url;
+
+/// Query parameters that only tell the destination where a visitor came from. A trailing
+/// `*` matches any parameter with that prefix.
+const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+    "utm_*",
+    "fbclid",
+    "gclid",
+    "gclsrc",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "msclkid",
+    "yclid",
+    "twclid",
+    "ttclid",
+    "li_fat_id",
+    "igshid",
+    "mc_cid",
+    "mc_eid",
+    "_hsenc",
+    "_hsmi",
+    "mkt_tok",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+    "_ga",
+    "_gl",
+];
+
+/// How many rows are canonicalized per query when filling in old links.
+const BACKFILL_BATCH: i64 = 1000;
+
+/// Bumped whenever `Canonicalizer::canonicalize` starts producing different results, so
+/// that stored canonical URLs are recomputed like after a configuration change.
+const VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrailingSlash {
+    Keep,
+    Strip,
+    Add,
+}
+
+/// Turns destinations into the form used to find an existing link for the same page, so
+/// that `https://Example.com/a/?utm_source=x` reuses the id of `https://example.com/a`.
+#[derive(Debug, Clone)]
+pub struct Canonicalizer {
+    enabled: bool,
+    trailing_slash: TrailingSlash,
+    tracking_params: Vec<String>,
+}
+
+impl Canonicalizer {
+    /// Reads `CHELA_CANONICALIZE_URLS` (`false` only reuses ids for identical URLs),
+    /// `CHELA_TRAILING_SLASH` and `CHELA_TRACKING_PARAMS`.
+    pub fn from_env() -> eyre::Result<Self> {
+        let enabled = env::var("CHELA_CANONICALIZE_URLS")
+            .map(|v| !matches!(v.to_lowercase().as_str(), "false" | "0" | "no" | "off"))
+            .unwrap_or(true);
+        let trailing_slash = match env::var("CHELA_TRAILING_SLASH")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "strip" => TrailingSlash::Strip,
+            "add" => TrailingSlash::Add,
+            "keep" => TrailingSlash::Keep,
+            other => {
+                return Err(eyre::eyre!(
+                    "CHELA_TRAILING_SLASH must be strip, add or keep, not '{}'",
+                    other
+                ))
+            }
+        };
+        let tracking_params = match env::var("CHELA_TRACKING_PARAMS") {
+            Ok(list) => list
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_TRACKING_PARAMS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+
+        Ok(Self {
+            enabled,
+            trailing_slash,
+            tracking_params,
+        })
+    }
+
+    /// The canonical form of `url`. The url crate already lowercases the scheme and the host
+    /// of http(s) URLs and drops their default ports.
+    pub fn canonicalize(&self, url: &Url) -> String {
+        if !self.enabled {
+            return url.to_string();
+        }
+
+        let mut url = url.clone();
+        if let Some(host) = url.host_str() {
+            let host = host.trim_end_matches('.').to_lowercase();
+            if Some(host.as_str()) != url.host_str() {
+                let _ = url.set_host(Some(&host));
+            }
+        }
+
+        if !url.cannot_be_a_base() {
+            let path = url.path().to_string();
+            match self.trailing_slash {
+                TrailingSlash::Keep => {}
+                TrailingSlash::Strip => {
+                    let trimmed = path.trim_end_matches('/');
+                    url.set_path(if trimmed.is_empty() { "/" } else { trimmed });
+                }
+                TrailingSlash::Add => {
+                    // Paths that look like files, e.g. `/report.pdf`, are left alone.
+                    let last = path.rsplit('/').next().unwrap_or_default();
+                    if !path.ends_with('/') && !last.contains('.') {
+                        url.set_path(&format!("{path}/"));
+                    }
+                }
+            }
+        }
+
+        let params: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(name, _)| !self.is_tracking_param(name))
+            .collect();
+        if params.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(params);
+        }
+
+        url.to_string()
+    }
+
+    /// Identifies the settings, and with them the canonical URLs they produce.
+    fn fingerprint(&self) -> String {
+        format!(
+            "v{VERSION} enabled={} trailing_slash={:?} tracking_params={}",
+            self.enabled,
+            self.trailing_slash,
+            self.tracking_params.join(",")
+        )
+    }
+
+    fn is_tracking_param(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.tracking_params
+            .iter()
+            .any(|param| match param.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == *param,
+            })
+    }
+}
+
+/// Fills in `canonical_url` for links created before it existed, and recomputes it for all
+/// links when the settings changed since the last start. Links whose stored URL doesn't
+/// parse are compared as they are.
+pub async fn backfill(db_pool: &Pool<Postgres>, canonicalizer: &Canonicalizer) -> eyre::Result<()> {
+    let fingerprint = canonicalizer.fingerprint();
+    let stored: Option<(String,)> =
+        sqlx::query_as("SELECT value FROM chela.settings WHERE name = 'canonicalizer'")
+            .fetch_optional(db_pool)
+            .await?;
+    if stored.as_ref().map(|(value,)| value) != Some(&fingerprint) {
+        let reset = sqlx::query(
+            "UPDATE chela.urls SET canonical_url = NULL WHERE canonical_url IS NOT NULL",
+        )
+        .execute(db_pool)
+        .await?;
+        if reset.rows_affected() > 0 {
+            info!("URL canonicalization changed, recomputing canonical URLs");
+        }
+    }
+
+    let mut total = 0;
+    loop {
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT index, url FROM chela.urls WHERE canonical_url IS NULL LIMIT $1",
+        )
+        .bind(BACKFILL_BATCH)
+        .fetch_all(db_pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        total += rows.len();
+        let (indexes, canonical): (Vec<i64>, Vec<String>) = rows
+            .into_iter()
+            .map(|(index, url)| {
+                let canonical = match Url::parse(&url) {
+                    Ok(parsed) => canonicalizer.canonicalize(&parsed),
+                    Err(_) => url,
+                };
+                (index, canonical)
+            })
+            .unzip();
+        sqlx::query(
+            "
+UPDATE chela.urls SET canonical_url = batch.canonical_url
+FROM unnest($1::BIGINT[], $2::TEXT[]) AS batch(index, canonical_url)
+WHERE urls.index = batch.index
+            ",
+        )
+        .bind(&indexes)
+        .bind(&canonical)
+        .execute(db_pool)
+        .await?;
+    }
+
+    if total > 0 {
+        info!("Canonicalized the URLs of {} existing links", total);
+    }
+    sqlx::query(
+        "
+INSERT INTO chela.settings (name,value) VALUES ('canonicalizer',$1)
+ON CONFLICT (name) DO UPDATE SET value = excluded.value
+        ",
+    )
+    .bind(&fingerprint)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{Canonicalizer, TrailingSlash, DEFAULT_TRACKING_PARAMS};
+
+    fn canonicalize(trailing_slash: TrailingSlash, url: &str) -> String {
+        let canonicalizer = Canonicalizer {
+            enabled: true,
+            trailing_slash,
+            tracking_params: DEFAULT_TRACKING_PARAMS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+        canonicalizer.canonicalize(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn normalizes_host_port_and_query() {
+        assert_eq!(
+            canonicalize(
+                TrailingSlash::Strip,
+                "HTTPS://Example.COM.:443/a/?utm_source=x&b=2&a=1&FBCLID=y"
+            ),
+            "https://example.com/a?b=2&a=1"
+        );
+        assert_eq!(
+            canonicalize(
+                TrailingSlash::Strip,
+                "http://example.com:8080/?utm_medium=x"
+            ),
+            "http://example.com:8080/"
+        );
+    }
+
+    #[test]
+    fn keeps_the_order_of_parameters() {
+        assert_eq!(
+            canonicalize(
+                TrailingSlash::Strip,
+                "https://example.com/?z=1&utm_campaign=x&a=2&z=0"
+            ),
+            "https://example.com/?z=1&a=2&z=0"
+        );
+    }
+
+    #[test]
+    fn keeps_paths_case_and_fragments() {
+        assert_eq!(
+            canonicalize(TrailingSlash::Strip, "https://example.com/A/B#Top"),
+            "https://example.com/A/B#Top"
+        );
+    }
+
+    #[test]
+    fn handles_trailing_slashes() {
+        let url = "https://example.com/a//";
+        assert_eq!(
+            canonicalize(TrailingSlash::Strip, url),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            canonicalize(TrailingSlash::Keep, url),
+            "https://example.com/a//"
+        );
+        assert_eq!(
+            canonicalize(TrailingSlash::Add, "https://example.com/a"),
+            "https://example.com/a/"
+        );
+        assert_eq!(
+            canonicalize(TrailingSlash::Add, "https://example.com/report.pdf"),
+            "https://example.com/report.pdf"
+        );
+    }
+
+    #[test]
+    fn leaves_urls_alone_when_disabled() {
+        let canonicalizer = Canonicalizer {
+            enabled: false,
+            trailing_slash: TrailingSlash::Strip,
+            tracking_params: vec![],
+        };
+        let url = Url::parse("https://Example.com/a/?utm_source=x").unwrap();
+        assert_eq!(
+            canonicalizer.canonicalize(&url),
+            "https://example.com/a/?utm_source=x"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::env;
+
+use axum::extract::Query;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::aliases;
+use crate::error::{Error, Result};
+use crate::proxy::ClientInfo;
+use crate::templates::{self, render};
+use crate::ServerState;
+use crate::UrlRow;
+
+/// User agent substrings of the crawlers that chat apps and social networks use to build
+/// link previews.
+const DEFAULT_BOTS: &[&str] = &[
+    "facebookexternalhit",
+    "facebookcatalog",
+    "twitterbot",
+    "slackbot",
+    "slack-imgproxy",
+    "discordbot",
+    "telegrambot",
+    "whatsapp",
+    "linkedinbot",
+    "skypeuripreview",
+    "microsoftpreview",
+    "redditbot",
+    "mastodon",
+    "embedly",
+    "iframely",
+    "pinterestbot",
+    "vkshare",
+    "mattermost",
+    "zulip",
+];
+
+#[derive(Debug, Clone)]
+pub struct UnfurlBots {
+    user_agents: Vec<String>,
+}
+
+impl UnfurlBots {
+    /// Reads `CHELA_UNFURL_BOTS`, a comma-separated list of user agent substrings that
+    /// replaces the default list.
+    pub fn from_env() -> Self {
+        let user_agents = match env::var("CHELA_UNFURL_BOTS") {
+            Ok(list) => list
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_BOTS.iter().map(|s| s.to_string()).collect(),
+        };
+        Self { user_agents }
+    }
+
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let Some(user_agent) = headers.get("user-agent").and_then(|it| it.to_str().ok()) else {
+            return false;
+        };
+        let user_agent = user_agent.to_lowercase();
+        self.user_agents.iter().any(|bot| user_agent.contains(bot))
+    }
+}
+
+/// Whether a link has any card fields set, so that bots should see our card rather than
+/// the destination's.
+pub fn has_card(link: &UrlRow) -> bool {
+    link.og_title.is_some() || link.og_description.is_some() || link.og_image.is_some()
+}
+
+/// The Open Graph and Twitter card page served to unfurl bots instead of the redirect.
+pub fn card_response(client: &ClientInfo, link: &UrlRow) -> Result<Response> {
+    let short_url = format!("{}/{}", client.base_url(), link.id);
+    let oembed_url = format!(
+        "{}/oembed?url={}",
+        client.base_url(),
+        url::form_urlencoded::byte_serialize(short_url.as_bytes()).collect::<String>()
+    );
+    Ok(render(&templates::Card {
+        host: &client.host,
+        short_url: &short_url,
+        url: &link.url,
+        title: card_title(link),
+        description: link.og_description.as_deref(),
+        image: link.og_image.as_deref(),
+        oembed_url: &oembed_url,
+    })?
+    .into_response())
+}
+
+fn card_title(link: &UrlRow) -> &str {
+    link.og_title
+        .as_deref()
+        .or(link.title.as_deref())
+        .unwrap_or(&link.url)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OEmbed<'a> {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    provider_name: &'a str,
+    provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<&'a str>,
+}
+
+/// An oEmbed provider for our own short links, e.g. `/oembed?url=https://a.com/abc`.
+pub async fn oembed(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Query(query): Query<OEmbedQuery>,
+) -> Result<Response> {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|f| !f.eq_ignore_ascii_case("json"))
+    {
+        return Ok((StatusCode::NOT_IMPLEMENTED, "Only JSON is supported.").into_response());
+    }
+
+    let url = Url::parse(&query.url)
+        .map_err(|err| Error::Validation(format!("Invalid url parameter: {err}")))?;
+    let domain = url
+        .host_str()
+        .and_then(|host| state.domains.find(host))
+        .ok_or_else(|| Error::NotFound(format!("'{}' is not a short link here.", query.url)))?;
+    let id = url
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .map(|segment| percent_encoding::percent_decode_str(segment).decode_utf8_lossy())
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| Error::NotFound(format!("'{}' is not a short link here.", query.url)))?;
+
+    let link = aliases::find_link(&state, &domain.name, &id)
+        .await?
+        .map(|found| found.link)
+        .filter(|link| link.status() == "active")
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?;
+
+    Ok(Json(OEmbed {
+        version: "1.0",
+        kind: "link",
+        title: card_title(&link),
+        description: link.og_description.as_deref(),
+        provider_name: &domain.name,
+        provider_url: format!("{}://{}", client.scheme, domain.name),
+        thumbnail_url: link.og_image.as_deref(),
+    })
+    .into_response())
+}
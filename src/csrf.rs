@@ -0,0 +1,163 @@
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponseParts, ResponseParts};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use url::Url;
+
+use tracing::warn;
+
+use crate::domains;
+use crate::error::Error;
+use crate::proxy::ClientInfo;
+
+const COOKIE_NAME: &str = "chela_csrf";
+
+/// The double-submit token for the current browser. Pages with forms embed it as a hidden
+/// `csrf_token` field, and returning it from a handler (re)sets the `chela_csrf` cookie.
+#[derive(Debug, Clone)]
+pub struct CsrfToken {
+    pub value: String,
+    secure: bool,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let client = client_info(parts, state).await?;
+        let value = cookie_token(&parts.headers)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        Ok(Self {
+            value,
+            secure: client.scheme == "https",
+        })
+    }
+}
+
+impl IntoResponseParts for CsrfToken {
+    type Error = Error;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let cookie = format!(
+            "{COOKIE_NAME}={}; Path=/; HttpOnly; SameSite=Strict{}",
+            self.value,
+            if self.secure { "; Secure" } else { "" }
+        );
+        let value = cookie
+            .parse()
+            .map_err(|err| eyre::eyre!("invalid CSRF cookie: {}", err))?;
+        res.headers_mut().append(header::SET_COOKIE, value);
+        Ok(res)
+    }
+}
+
+/// A form body that is only accepted if it was posted from one of our own pages: the
+/// `Origin` (or `Referer`) must be this domain, and the `csrf_token` field must match the
+/// `chela_csrf` cookie.
+pub struct CsrfForm<T>(pub T);
+
+#[derive(Deserialize)]
+struct TokenField {
+    csrf_token: Option<String>,
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for CsrfForm<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = request.into_parts();
+        let client = client_info(&mut parts, state).await?;
+        check_origin(&parts.headers, &client)?;
+        let cookie = cookie_token(&parts.headers);
+
+        let bytes = Bytes::from_request(Request::from_parts(parts, body), state)
+            .await
+            .map_err(|err| Error::Validation(format!("Failed to read form: {err}")))?;
+        let field: TokenField = serde_urlencoded::from_bytes(&bytes)
+            .map_err(|err| Error::Validation(format!("Invalid form: {err}")))?;
+        match (cookie, field.csrf_token) {
+            (Some(cookie), Some(token)) if constant_time_eq(&cookie, &token) => {}
+            _ => {
+                warn!(
+                    "Rejected form from {} with a missing or wrong CSRF token",
+                    client.ip
+                );
+                return Err(Error::Forbidden(
+                    "This form has expired. Reload the page and try again.".to_string(),
+                ));
+            }
+        }
+
+        let form = serde_urlencoded::from_bytes(&bytes)
+            .map_err(|err| Error::Validation(format!("Invalid form: {err}")))?;
+        Ok(Self(form))
+    }
+}
+
+async fn client_info<S: Send + Sync>(parts: &mut Parts, state: &S) -> Result<ClientInfo, Error> {
+    ClientInfo::from_request_parts(parts, state)
+        .await
+        .map_err(|(_, message)| Error::Internal(eyre::eyre!(message)))
+}
+
+/// Browsers send `Origin` with every cross-site POST. Requests from non-browser clients may
+/// have neither header and are left to the token check.
+fn check_origin(headers: &HeaderMap, client: &ClientInfo) -> Result<(), Error> {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|it| it.to_str().ok());
+    let Some(source) = source else {
+        return Ok(());
+    };
+
+    let host = Url::parse(source)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    let request_host = headers.get(header::HOST).and_then(|it| it.to_str().ok());
+    let allowed = host.is_some_and(|host| {
+        domains::same_host(&host, &client.host)
+            || request_host.is_some_and(|request_host| domains::same_host(&host, request_host))
+    });
+    if allowed {
+        return Ok(());
+    }
+    warn!("Rejected form from {} posted from '{}'", client.ip, source);
+    Err(Error::Forbidden(
+        "Cross-site form submissions are not allowed.".to_string(),
+    ))
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
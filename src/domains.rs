@@ -0,0 +1,79 @@
+use std::env;
+
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub struct Domain {
+    pub name: String,
+    pub main_page_redirect: Option<Url>,
+}
+
+/// The domains served by this instance. The first one is `CHELA_HOST`, which requests for
+/// unknown hosts fall back to.
+#[derive(Debug, Clone)]
+pub struct Domains {
+    list: Vec<Domain>,
+}
+
+impl Domains {
+    /// Reads `CHELA_DOMAINS`, a comma-separated list of `domain` or `domain=redirect` entries
+    /// served in addition to `host`.
+    pub fn from_env(host: &str) -> eyre::Result<Self> {
+        let main_page_redirect = env::var("CHELA_MAIN_PAGE_REDIRECT").unwrap_or_default();
+        let mut list = vec![Domain {
+            name: host.to_string(),
+            main_page_redirect: Url::parse(&main_page_redirect).ok(),
+        }];
+
+        for entry in env::var("CHELA_DOMAINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let (name, redirect) = match entry.split_once('=') {
+                Some((name, redirect)) => (name.trim(), Some(Url::parse(redirect.trim())?)),
+                None => (entry, None),
+            };
+            if list.iter().any(|d| same_host(&d.name, name)) {
+                continue;
+            }
+            list.push(Domain {
+                name: name.to_string(),
+                main_page_redirect: redirect,
+            });
+        }
+
+        Ok(Self { list })
+    }
+
+    pub fn primary(&self) -> &Domain {
+        &self.list[0]
+    }
+
+    pub fn all(&self) -> &[Domain] {
+        &self.list
+    }
+
+    /// Finds the configured domain for a `Host` header value, ignoring case and port.
+    pub fn find(&self, host: &str) -> Option<&Domain> {
+        self.list.iter().find(|d| same_host(&d.name, host))
+    }
+
+    /// Like `find`, but falls back to the primary domain.
+    pub fn resolve(&self, host: Option<&str>) -> &Domain {
+        host.and_then(|h| self.find(h))
+            .unwrap_or_else(|| self.primary())
+    }
+}
+
+pub fn same_host(a: &str, b: &str) -> bool {
+    strip_port(a).eq_ignore_ascii_case(strip_port(b))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
@@ -0,0 +1,136 @@
+use askama::Template;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+use tracing::warn;
+
+use crate::templates;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the request handlers. Each variant maps to a status code and is
+/// rendered as an HTML error page, or as JSON when the client asks for it.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Gone(String),
+    #[error("database error: {0}")]
+    Database(sqlx::Error),
+    #[error("{0}")]
+    Internal(eyre::Report),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Gone(_) => StatusCode::GONE,
+            Error::Database(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The message shown to the client. Server-side failures are only described in the log.
+    fn public_message(&self) -> String {
+        match self {
+            Error::Database(_) | Error::Internal(_) => "Internal error.".to_string(),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::NotFound("Not found.".to_string()),
+            sqlx::Error::Database(ref db) if db.is_unique_violation() => {
+                Error::Conflict("That id is already taken.".to_string())
+            }
+            other => Error::Database(other),
+        }
+    }
+}
+
+impl From<eyre::Report> for Error {
+    fn from(err: eyre::Report) -> Self {
+        Error::Internal(err)
+    }
+}
+
+/// The rendered error, kept on the response so that [`negotiate`] can swap the HTML page
+/// for JSON.
+#[derive(Debug, Clone, Serialize)]
+struct ErrorBody {
+    status: u16,
+    error: &'static str,
+    message: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            warn!("{}", self);
+        }
+
+        let body = ErrorBody {
+            status: status.as_u16(),
+            error: status.canonical_reason().unwrap_or("Error"),
+            message: self.public_message(),
+        };
+        let page = templates::ErrorPage {
+            status: body.status,
+            error: body.error,
+            message: &body.message,
+        };
+        let html = page
+            .render()
+            .unwrap_or_else(|_| format!("{} {}", body.status, body.error));
+
+        let mut response = (status, Html(html)).into_response();
+        response.extensions_mut().insert(body);
+        response
+    }
+}
+
+/// Re-renders error pages as JSON for clients whose `Accept` header prefers
+/// `application/json` over HTML.
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let wants_json = prefers_json(request.headers());
+
+    let mut response = next.run(request).await;
+    if !wants_json {
+        return response;
+    }
+    match response.extensions_mut().remove::<ErrorBody>() {
+        Some(body) => (response.status(), Json(body)).into_response(),
+        None => response,
+    }
+}
+
+/// Whether the `Accept` header lists `application/json` before `text/html`.
+pub fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|it| it.to_str().ok()) else {
+        return false;
+    };
+    let position = |mime: &str| accept.find(mime);
+    match (position("application/json"), position("text/html")) {
+        (Some(json), Some(html)) => json < html,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
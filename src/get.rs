@@ -1,469 +1,454 @@
 use std::collections::hash_map::HashMap;
-use std::net::SocketAddr;
 
-use axum::extract::{ConnectInfo, Path};
-use axum::http::HeaderMap;
-use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse, Redirect};
+use axum::extract::Path;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::Extension;
 
-use info_utils::prelude::*;
-
+use eyre::eyre;
+use tracing::{info, warn, Instrument};
+
+use crate::aliases;
+use crate::cards;
+use crate::csrf::CsrfToken;
+use crate::error::{Error, Result};
+use crate::ids::Strategy;
+use crate::logging;
+use crate::preview;
+use crate::proxy::ClientInfo;
+use crate::schedule::{self, Availability};
+use crate::screening::ScreenAction;
+use crate::targeting::{self, Field};
+use crate::telemetry;
+use crate::templates::{self, render};
+use crate::variants;
+use crate::LinkStats;
 use crate::ServerState;
 use crate::TrackingRow;
-use crate::UdsConnectInfo;
 use crate::UrlRow;
+use crate::VariantRow;
 
-enum TrackingParameter {
-    Ip,
-    Referrer,
-    UserAgent,
-}
-
-pub async fn index(Extension(state): Extension<ServerState>) -> impl IntoResponse {
-    if let Some(redirect) = state.main_page_redirect {
-        return Redirect::temporary(redirect.as_str()).into_response();
-    }
-
-    Html(format!(
-        r#"
-    <!DOCTYPE html>
-    <html>
-        <head>
-            <title>{} URL Shortener</title>
-        </head>
-    </html>
-    <body>
-        <pre>{} URL shortener</pre>
-        <a href="/create">create</a>
-    </body>
-         "#,
-        state.host, state.host
-    ))
-    .into_response()
-}
-
-pub async fn id_unix(
-    headers: HeaderMap,
-    ConnectInfo(addr): ConnectInfo<UdsConnectInfo>,
+pub async fn index(
+    client: ClientInfo,
     Extension(state): Extension<ServerState>,
-    Path(id): Path<String>,
 ) -> impl IntoResponse {
-    let ip = if state.behind_proxy {
-        match headers.get("x-real-ip") {
-            Some(it) => {
-                if let Ok(i) = it.to_str() {
-                    Some(i.to_string())
-                } else {
-                    None
-                }
-            }
-            None => None,
-        }
-    } else {
-        Some(format!("{:?}", addr.peer_addr))
+    let domain = state.domains.resolve(Some(&client.host));
+    if let Some(redirect) = &domain.main_page_redirect {
+        return Redirect::temporary(redirect.as_str()).into_response();
     }
-    .unwrap_or_default();
-    run_id(headers, ip, state, id).await
+
+    render(&templates::Index { host: &client.host }).into_response()
 }
 
-/// # Panics
-/// Will panic if `parse()` fails
 pub async fn id(
     headers: HeaderMap,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
     Extension(state): Extension<ServerState>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    let ip = get_ip(&headers, addr, &state).unwrap_or_default();
-    run_id(headers, ip, state, id).await
+) -> Result<Response> {
+    run_id(headers, client, state, id).await
 }
 
 async fn run_id(
     headers: HeaderMap,
-    ip: String,
+    client: ClientInfo,
     state: ServerState,
     id: String,
-) -> impl IntoResponse {
+) -> Result<Response> {
     let mut show_request = false;
-    log!("Request for '{}' from {}", id.clone(), ip);
+    info!("Request for '{}' from {}", id.clone(), client.ip);
     let mut use_id = id;
     if use_id.ends_with('+') {
         show_request = true;
         use_id.pop();
     }
+    logging::record_link_id(&use_id);
 
-    let item: Result<UrlRow, sqlx::Error> =
-        sqlx::query_as("SELECT * FROM chela.urls WHERE id = $1")
-            .bind(use_id.clone())
-            .fetch_one(&state.db_pool)
-            .await;
-    if let Ok(it) = item {
-        if url::Url::parse(&it.url).is_ok() {
-            if show_request {
-                return Html(format!(
-                    r#"<pre>http://{}/{} -> <a href="{}"">{}</a></pre>"#,
-                    state.host, it.id, it.url, it.url
-                ))
-                .into_response();
-            }
-            log!("Redirecting {} -> {}", it.id, it.url);
-            save_analytics(headers, it.clone(), ip, state).await;
-            let mut response_headers = HeaderMap::new();
-            response_headers.insert("Cache-Control", "private, max-age=90".parse().unwrap());
-            response_headers.insert("Location", it.url.parse().unwrap());
-            return (
-                StatusCode::MOVED_PERMANENTLY,
-                response_headers,
-                Html(format!(
-                    r#"Redirecting to <a href="{}">{}</a>"#,
-                    it.url, it.url
-                )),
-            )
-                .into_response();
+    let item = aliases::find_link(&state, &client.host, &use_id).await?;
+    let Some(found) = item else {
+        warn!("'{}' not found on {}.", use_id, client.host);
+        state.metrics.redirects.with_label_values(&["miss"]).inc();
+        return Err(Error::NotFound(format!("No link with id '{use_id}'.")));
+    };
+    // Aliases share their link's row. From here on `id` is the alias that was visited, so
+    // that it is what analytics and the pages below show.
+    let mut it = found.link;
+    it.id = found.alias;
+    if it.disabled {
+        info!("'{}' is disabled", it.id);
+        state
+            .metrics
+            .redirects
+            .with_label_values(&["disabled"])
+            .inc();
+        return Err(Error::Gone("This link has been disabled.".to_string()));
+    }
+    match schedule::availability(&it, chrono::Utc::now()) {
+        Availability::Active => {}
+        Availability::Pending => {
+            info!("'{}' isn't active yet", it.id);
+            state
+                .metrics
+                .redirects
+                .with_label_values(&["pending"])
+                .inc();
+            return pending_response(&state, &it);
+        }
+        Availability::Expired => {
+            info!("'{}' has expired", it.id);
+            state
+                .metrics
+                .redirects
+                .with_label_values(&["expired"])
+                .inc();
+            return Err(Error::Gone("This link has expired.".to_string()));
         }
-    } else {
-        warn!("'{}' not found.", use_id);
-        return (
-            StatusCode::NOT_FOUND,
-            Html("<pre>Not found.</pre>".to_string()),
-        )
-            .into_response();
     }
 
-    (StatusCode::NOT_FOUND, Html("<pre>Not found.</pre>")).into_response()
-}
+    let url = url::Url::parse(&it.url)
+        .map_err(|err| eyre!("stored URL for '{}' is invalid: {}", it.id, err))?;
+    if let Some(reason) = state.screener.check(&url) {
+        warn!("'{}' -> {} is flagged: {}", it.id, it.url, reason);
+        state
+            .metrics
+            .redirects
+            .with_label_values(&["flagged"])
+            .inc();
+        return flagged_response(&state, &it.domain, &it.url);
+    }
+    if show_request {
+        return preview::respond(&headers, &client, &state, it, &url).await;
+    }
+    if cards::has_card(&it) && state.unfurl_bots.matches(&headers) {
+        info!("Serving card for '{}' to an unfurl bot", it.id);
+        state.metrics.redirects.with_label_values(&["unfurl"]).inc();
+        return cards::card_response(&client, &it);
+    }
 
-async fn save_analytics(headers: HeaderMap, item: UrlRow, ip: String, state: ServerState) {
-    let id = item.id;
-    let referer = match headers.get("referer") {
-        Some(it) => {
-            if let Ok(i) = it.to_str() {
-                Some(i)
-            } else {
-                None
-            }
+    // Targeting rules come first. Visitors that none of them match are split across the
+    // link's variants, if it has any, or get its own destination.
+    let link_rules = targeting::list(&state, &it).await?;
+    let rule = if link_rules.is_empty() {
+        None
+    } else {
+        let visitor =
+            targeting::Visitor::new(&headers, &client, &state.geoip, state.schedule.now());
+        targeting::first_match(&link_rules, &visitor)
+    };
+    let link_variants = match rule {
+        Some(_) => Vec::new(),
+        None => variants::list(&state, &it).await?,
+    };
+    let choice = if link_variants.is_empty() {
+        None
+    } else {
+        Some(variants::choose(&headers, &client, &it, &link_variants)?)
+    };
+    let destination = match (rule, &choice) {
+        (Some(rule), _) => {
+            info!("'{}' matched rule {} ({})", it.id, rule.id, rule.field);
+            Some(rule.url.as_str())
         }
-        None => None,
+        (None, Some(choice)) => Some(choice.url.as_str()),
+        (None, None) => None,
     };
-    let user_agent = match headers.get("user-agent") {
-        Some(it) => {
-            if let Ok(i) = it.to_str() {
-                Some(i)
-            } else {
-                None
-            }
+    let mut url = url;
+    if let Some(destination) = destination.filter(|destination| *destination != it.url) {
+        url = url::Url::parse(destination).map_err(|err| {
+            eyre!(
+                "stored destination {} of '{}' is invalid: {}",
+                destination,
+                it.id,
+                err
+            )
+        })?;
+        if let Some(reason) = state.screener.check(&url) {
+            warn!("'{}' -> {} is flagged: {}", it.id, destination, reason);
+            state
+                .metrics
+                .redirects
+                .with_label_values(&["flagged"])
+                .inc();
+            return flagged_response(&state, &it.domain, destination);
+        }
+    }
+
+    let location = HeaderValue::try_from(url.as_str())
+        .map_err(|err| eyre!("stored URL for '{}' is not a valid header: {}", it.id, err))?;
+    info!("Redirecting {} -> {}", it.id, url);
+    state.metrics.redirects.with_label_values(&["hit"]).inc();
+    let variant = choice.as_ref().map(|choice| choice.name.clone());
+    save_analytics(headers, it.clone(), variant, client.ip, state).await;
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert("Location", location);
+    // Where split and targeted links lead depends on the visitor, so they are never cached.
+    // This way every visit is also assigned and counted.
+    let status = if !link_rules.is_empty() || choice.is_some() {
+        response_headers.insert(
+            "Cache-Control",
+            HeaderValue::from_static("private, no-store"),
+        );
+        if let Some(cookie) = choice.and_then(|choice| choice.cookie) {
+            response_headers.insert("Set-Cookie", cookie);
         }
-        None => None,
+        StatusCode::FOUND
+    } else {
+        response_headers.insert(
+            "Cache-Control",
+            HeaderValue::from_static("private, max-age=90"),
+        );
+        StatusCode::MOVED_PERMANENTLY
     };
+    Ok((
+        status,
+        response_headers,
+        render(&templates::Redirect { url: url.as_str() })?,
+    )
+        .into_response())
+}
+
+/// Links that aren't active yet don't reveal where they lead. Visitors either get a page
+/// that says when the link becomes available or are sent to `CHELA_NOT_YET_AVAILABLE_URL`.
+fn pending_response(state: &ServerState, item: &UrlRow) -> Result<Response> {
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        "Cache-Control",
+        HeaderValue::from_static("private, no-store"),
+    );
+    if let Some(url) = state.schedule.pending_url() {
+        let location = HeaderValue::try_from(url.as_str())
+            .map_err(|err| eyre!("CHELA_NOT_YET_AVAILABLE_URL is not a valid header: {}", err))?;
+        response_headers.insert("Location", location);
+        return Ok((StatusCode::FOUND, response_headers).into_response());
+    }
+
+    let available_from = item
+        .active_from
+        .map(|from| state.schedule.display(from))
+        .unwrap_or_default();
+    Ok((
+        StatusCode::NOT_FOUND,
+        response_headers,
+        render(&templates::Pending {
+            host: &item.domain,
+            available_from: &available_from,
+        })?,
+    )
+        .into_response())
+}
+
+fn flagged_response(state: &ServerState, host: &str, url: &str) -> Result<Response> {
+    match state.screener.action {
+        ScreenAction::Block => Err(Error::Gone(
+            "This link has been disabled because its destination is flagged as malicious."
+                .to_string(),
+        )),
+        ScreenAction::Warn => Ok(render(&templates::Warning { host, url })?.into_response()),
+    }
+}
+
+async fn save_analytics(
+    headers: HeaderMap,
+    item: UrlRow,
+    variant: Option<String>,
+    ip: String,
+    state: ServerState,
+) {
+    let domain = item.domain;
+    let id = item.id;
+    let referer = headers.get("referer").and_then(|it| it.to_str().ok());
+    let user_agent = headers.get("user-agent").and_then(|it| it.to_str().ok());
 
     let res = sqlx::query(
         "
-INSERT INTO chela.tracking (id,ip,referrer,user_agent) 
-VALUES ($1,$2,$3,$4)
+INSERT INTO chela.tracking (domain,id,ip,referrer,user_agent,variant) 
+VALUES ($1,$2,$3,$4,$5,$6)
        ",
     )
+    .bind(domain)
     .bind(id.clone())
     .bind(ip.clone())
     .bind(referer)
     .bind(user_agent)
+    .bind(variant)
     .execute(&state.db_pool)
+    .instrument(telemetry::db_span("INSERT", "chela.tracking"))
     .await;
 
-    if res.is_ok() {
-        log!("Saved analytics for '{id}' from {}", ip);
-    }
-}
-
-fn get_ip(headers: &HeaderMap, addr: SocketAddr, state: &ServerState) -> Option<String> {
-    if state.behind_proxy {
-        match headers.get("x-real-ip") {
-            Some(it) => {
-                if let Ok(i) = it.to_str() {
-                    Some(i.to_string())
-                } else {
-                    None
-                }
-            }
-            None => None,
+    match res {
+        Ok(_) => info!("Saved analytics for '{id}' from {}", ip),
+        Err(err) => {
+            warn!("Failed to save analytics for '{id}': {}", err);
+            state.metrics.analytics_failures.inc();
         }
-    } else {
-        Some(addr.ip().to_string())
     }
 }
 
-pub async fn create_id(Extension(state): Extension<ServerState>) -> Html<String> {
-    Html(format!(
-        r#"
-        <!DOCTYPE html>
-        <html>
-            <head>
-                <title>{} URL Shortener</title>
-            </head>
-            <body>
-                <form action="/" method="post">
-                    <label for="url">
-                        URL to shorten:
-                        <input type="url" name="url" required>
-                    </label>
-                    <br />
-                    <label for="id">
-                        ID (optional):
-                        <input type="text" name="id">
-                    </label>
-                    <br />
-                    <input type="submit" value="create">
-                </form>
-            </body>
-        </html>
-         "#,
-        state.host
-    ))
+pub async fn create_id(
+    client: ClientInfo,
+    csrf: CsrfToken,
+    Extension(state): Extension<ServerState>,
+) -> Result<(CsrfToken, Html<String>)> {
+    let page = render(&templates::Create {
+        host: &client.host,
+        csrf_token: &csrf.value,
+        domains: state.domains.all().iter().map(|d| d.name.clone()).collect(),
+        strategies: Strategy::ALL.map(|s| s.name().to_string()).into(),
+        default_strategy: state.ids.default_strategy.name(),
+    })?;
+    Ok((csrf, page))
 }
 
-pub async fn tracking(Extension(state): Extension<ServerState>) -> impl IntoResponse {
-    let url_rows: Vec<UrlRow> = sqlx::query_as("SELECT * FROM chela.urls")
-        .fetch_all(&state.db_pool)
-        .await
-        .unwrap();
-    let html = format!(
-        r#"
-            <!DOCTYPE html>
-            <html>
-                <head>
-                    <title>{} Tracking</title>
-                </head>
-                <style>{}</style>
-                <body>
-                    {}
-                </body>
-            </html>
-            "#,
-        state.host,
-        table_css(),
-        make_table_from_urls(&url_rows)
-    );
-
-    return Html(html).into_response();
+pub async fn tracking(
+    client: ClientInfo,
+    csrf: CsrfToken,
+    Extension(state): Extension<ServerState>,
+) -> Result<(CsrfToken, Html<String>)> {
+    let links: Vec<LinkStats> = sqlx::query_as(
+        "
+SELECT urls.*, count(tracking.id) AS clicks
+FROM chela.urls
+JOIN chela.aliases ON aliases.link = urls.index
+LEFT JOIN chela.tracking ON tracking.domain = aliases.domain AND tracking.id = aliases.id
+WHERE urls.domain = $1
+GROUP BY urls.index
+ORDER BY urls.index DESC
+        ",
+    )
+    .bind(&client.host)
+    .fetch_all(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.urls"))
+    .await?;
+
+    let page = render(&templates::Tracking {
+        host: &client.host,
+        csrf_token: &csrf.value,
+        base_url: &client.base_url(),
+        domains: state.domains.all().iter().map(|d| d.name.clone()).collect(),
+        strategies: Strategy::ALL.map(|s| s.name().to_string()).into(),
+        default_strategy: state.ids.default_strategy.name(),
+        links: &links,
+    })?;
+    Ok((csrf, page))
 }
 
 pub async fn tracking_id(
+    client: ClientInfo,
+    csrf: CsrfToken,
     Extension(state): Extension<ServerState>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    let tracking_rows: Vec<TrackingRow> =
-        sqlx::query_as("SELECT * FROM chela.tracking WHERE id = $1")
-            .bind(id.clone())
-            .fetch_all(&state.db_pool)
-            .await
-            .unwrap();
-    let url: UrlRow = sqlx::query_as("SELECT * FROM chela.urls WHERE id = $1")
-        .bind(id.clone())
-        .fetch_one(&state.db_pool)
-        .await
-        .unwrap();
-
-    let html = format!(
-        r#"
-            <!DOCTYPE html>
-            <html>
-                <head>
-                    <title>{} Tracking {}</title>
-                </head>
-                <style>{}</style>
-                <body>
-                    <h1>Tracking for <a href="{}">{}</a> from ID '{}'</h1>
-                    <h2>Visited {} times</h2>
-                    {}
-
-                    <h2>By IP</h2>
-                    {}
-                    <h2>By Referrer</h2>
-                    {}
-                    <h2>By User Agent</h2>
-                    {}
-                </body>
-            </html>
-            "#,
-        state.host,
-        id,
-        table_css(),
-        url.url,
-        url.url,
-        url.id,
-        tracking_rows.len(),
-        make_table_from_tracking(&tracking_rows),
-        make_grouped_table_from_tracking(&tracking_rows, TrackingParameter::Ip),
-        make_grouped_table_from_tracking(&tracking_rows, TrackingParameter::Referrer),
-        make_grouped_table_from_tracking(&tracking_rows, TrackingParameter::UserAgent)
-    );
-
-    return Html(html).into_response();
+) -> Result<(CsrfToken, Html<String>)> {
+    let url = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+    let link_aliases = aliases::list(&state, &url).await?;
+    let tracking_rows: Vec<TrackingRow> = sqlx::query_as(
+        "
+SELECT tracking.*
+FROM chela.tracking
+JOIN chela.aliases ON aliases.domain = tracking.domain AND aliases.id = tracking.id
+WHERE aliases.link = $1
+ORDER BY tracking.timestamp
+        ",
+    )
+    .bind(url.index)
+    .fetch_all(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.tracking"))
+    .await?;
+    let by_alias = link_aliases
+        .iter()
+        .map(|alias| {
+            let clicks = tracking_rows
+                .iter()
+                .filter(|row| row.id == alias.id)
+                .count();
+            (alias.id.clone(), clicks)
+        })
+        .collect();
+    let link_variants = variants::list(&state, &url).await?;
+    let by_variant = variant_stats(&url, &link_variants, &tracking_rows);
+    let rules = targeting::list(&state, &url).await?;
+
+    let page = render(&templates::TrackingId {
+        host: &client.host,
+        csrf_token: &csrf.value,
+        base_url: &client.base_url(),
+        link: &url,
+        visits: &tracking_rows,
+        by_alias,
+        by_variant,
+        rules: &rules,
+        targeting_fields: Field::ALL.map(|f| f.name().to_string()).into(),
+        has_geoip: state.geoip.is_enabled(),
+        active_from: state.schedule.format_local(url.active_from),
+        active_until: state.schedule.format_local(url.active_until),
+        time_zone: state.schedule.time_zone.name(),
+        by_ip: count_by(&tracking_rows, |row| row.ip.as_deref()),
+        by_referrer: count_by(&tracking_rows, |row| row.referrer.as_deref()),
+        by_user_agent: count_by(&tracking_rows, |row| row.user_agent.as_deref()),
+    })?;
+    Ok((csrf, page))
 }
 
-fn make_table_from_tracking(rows: &Vec<TrackingRow>) -> String {
-    let mut html = r#"<table>
-                        <colgroup>
-                            <col>
-                            <col>
-                            <col>
-                            <col>
-                            <col>
-                        </colgroup>
-                        <tr>
-                            <th>Timestamp</th>
-                            <th>ID</th>
-                            <th>IP</th>
-                            <th>Referrer</th>
-                            <th>User Agent</th>
-                        </tr>
-                    "#
-    .to_string();
-
-    for row in rows {
-        html += &format!(
-            r#"
-                <tr>
-                    <td>{}</td>
-                    <td>{}</td>
-                    <td>{}</td>
-                    <td>{}</td>
-                    <td>{}</td>
-                </tr>
-                         "#,
-            row.timestamp,
-            row.id,
-            row.ip.as_ref().unwrap_or(&String::default()),
-            row.referrer.as_ref().unwrap_or(&String::default()),
-            row.user_agent.as_ref().unwrap_or(&String::default())
+/// Clicks per destination of a split link, including ones that have since been removed.
+/// Links that were never split have none.
+fn variant_stats(
+    link: &UrlRow,
+    link_variants: &[VariantRow],
+    rows: &[TrackingRow],
+) -> Vec<templates::VariantStats> {
+    let mut stats = Vec::new();
+    if !link_variants.is_empty() {
+        let total: i32 = link.weight + link_variants.iter().map(|v| v.weight).sum::<i32>();
+        let current = std::iter::once((variants::PRIMARY, link.url.as_str(), link.weight)).chain(
+            link_variants
+                .iter()
+                .map(|v| (v.name.as_str(), v.url.as_str(), v.weight)),
         );
+        for (name, url, weight) in current {
+            stats.push(templates::VariantStats {
+                name: name.to_string(),
+                url: Some(url.to_string()),
+                weight,
+                share: (weight * 100 + total / 2) / total,
+                clicks: 0,
+            });
+        }
     }
-
-    html += r#"
-        </table>
-        "#;
-
-    html
-}
-
-fn make_grouped_table_from_tracking(rows: &Vec<TrackingRow>, group: TrackingParameter) -> String {
-    let column_name = match group {
-        TrackingParameter::Ip => "IP",
-        TrackingParameter::Referrer => "Referrer",
-        TrackingParameter::UserAgent => "User Agent",
-    }
-    .to_string();
-
-    let mut html = format!(
-        r#"
-            <table>
-                <colgroup>
-                    <col>
-                    <col>
-                </colgroup>
-                <tr>
-                    <th>Occurrences</th>
-                    <th>{}</th>
-                </tr>
-                    "#,
-        column_name
-    );
-
-    let mut aggregate: HashMap<String, u32> = HashMap::new();
-
     for row in rows {
-        let tracker = match group {
-            TrackingParameter::Ip => {
-                let v = match &row.ip {
-                    Some(val) => val,
-                    None => continue,
-                };
-                v
-            }
-            TrackingParameter::Referrer => {
-                let v = match &row.referrer {
-                    Some(val) => val,
-                    None => continue,
-                };
-                v
-            }
-            TrackingParameter::UserAgent => {
-                let v = match &row.user_agent {
-                    Some(val) => val,
-                    None => continue,
-                };
-                v
-            }
+        let Some(name) = row.variant.as_deref() else {
+            continue;
         };
-        let count = aggregate.get(tracker).unwrap_or(&0);
-        aggregate.insert(tracker.to_string(), count + 1);
-    }
-
-    for (key, val) in aggregate {
-        html += &format!(
-            r#"
-                <tr>
-                    <td>{}</td>
-                    <td>{}</td>
-                </tr>
-                         "#,
-            val, key
-        );
+        match stats.iter_mut().find(|stat| stat.name == name) {
+            Some(stat) => stat.clicks += 1,
+            None => stats.push(templates::VariantStats {
+                name: name.to_string(),
+                url: None,
+                weight: 0,
+                share: 0,
+                clicks: 1,
+            }),
+        }
     }
-
-    html += r#"
-        </table>
-        "#;
-
-    html
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
 }
 
-fn make_table_from_urls(urls: &Vec<UrlRow>) -> String {
-    let mut html = r#"<table>
-                        <colgroup>
-                            <col>
-                            <col>
-                            <col>
-                            <col>
-                        </colgroup>
-                        <tr>
-                            <th>Index</th>
-                            <th>ID</th>
-                            <th>URL</th>
-                            <th>Custom ID</th>
-                        </tr>
-                    "#
-    .to_string();
-
-    for url in urls {
-        html += &format!(
-            r#"
-                <tr>
-                    <td>{}</td>
-                    <td><a href="/tracking/{}">{}</a></td>
-                    <td><a href="{}">{}</a></td>
-                    <td>{}</td>
-                </tr>
-                         "#,
-            url.index, url.id, url.id, url.url, url.url, url.custom_id
-        );
+/// Counts how often each value of a tracking column occurs, most frequent first.
+fn count_by(
+    rows: &[TrackingRow],
+    column: impl Fn(&TrackingRow) -> Option<&str>,
+) -> Vec<(String, u32)> {
+    let mut aggregate: HashMap<&str, u32> = HashMap::new();
+    for value in rows.iter().filter_map(&column) {
+        *aggregate.entry(value).or_default() += 1;
     }
-    html += r#"
-        </table>
-        "#;
-
-    html
-}
 
-fn table_css() -> String {
-    r#"
-        tr:nth-child(even) {
-          background: #f2f2f2;
-        }
-
-        table, th, td {
-            border: 1px solid black;
-        }
-        "#
-    .to_string()
+    let mut counts: Vec<(String, u32)> = aggregate
+        .into_iter()
+        .map(|(value, count)| (value.to_string(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
 }
@@ -0,0 +1,86 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse};
+use axum::Extension;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+use tracing::warn;
+
+use crate::ServerState;
+
+pub async fn healthz() -> impl IntoResponse {
+    Html("<pre>ok</pre>")
+}
+
+/// Ready once the database is reachable and has the tables and columns chela expects.
+pub async fn readyz(Extension(state): Extension<ServerState>) -> impl IntoResponse {
+    let checks = [
+        "SELECT 1",
+        "SELECT index, domain, id, url, custom_id FROM chela.urls LIMIT 0",
+        "SELECT timestamp, domain, id, ip, referrer, user_agent FROM chela.tracking LIMIT 0",
+        "SELECT domain, id, link FROM chela.aliases LIMIT 0",
+    ];
+    for check in checks {
+        if let Err(err) = sqlx::query(check).execute(&state.db_pool).await {
+            warn!("Readiness check failed: {}", err);
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Html("<pre>not ready</pre>"),
+            )
+                .into_response();
+        }
+    }
+
+    Html("<pre>ok</pre>").into_response()
+}
+
+/// Implements `chela healthcheck [--ready]` for images without curl: requests `/healthz`
+/// (or `/readyz`) from the local server and exits non-zero unless it answers `200`.
+pub async fn run_healthcheck(ready: bool) -> eyre::Result<()> {
+    let path = if ready { "/readyz" } else { "/healthz" };
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+    let unix_socket = env::var("CHELA_UNIX_SOCKET").unwrap_or_default();
+    let response = tokio::time::timeout(Duration::from_secs(5), async {
+        if unix_socket.is_empty() {
+            let address = env::var("CHELA_LISTEN_ADDRESS").unwrap_or("0.0.0.0".to_string());
+            let stream = match address.trim_matches(['[', ']']).parse::<IpAddr>() {
+                // A server listening on all interfaces is reached through loopback.
+                Ok(IpAddr::V4(ip)) if ip.is_unspecified() => {
+                    TcpStream::connect((Ipv4Addr::LOCALHOST, 3000)).await?
+                }
+                Ok(IpAddr::V6(ip)) if ip.is_unspecified() => {
+                    TcpStream::connect((Ipv6Addr::LOCALHOST, 3000)).await?
+                }
+                Ok(ip) => TcpStream::connect(SocketAddr::new(ip, 3000)).await?,
+                Err(_) => TcpStream::connect((address.as_str(), 3000)).await?,
+            };
+            send_request(stream, &request).await
+        } else {
+            let stream = UnixStream::connect(&unix_socket).await?;
+            send_request(stream, &request).await
+        }
+    })
+    .await??;
+
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) == Some("200") {
+        Ok(())
+    } else {
+        Err(eyre::eyre!("{} returned '{}'", path, status))
+    }
+}
+
+async fn send_request<S>(mut stream: S, request: &str) -> eyre::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    Ok(String::from_utf8_lossy(&response).to_string())
+}
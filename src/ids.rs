@@ -0,0 +1,723 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use eyre::eyre;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqids::Sqids;
+use sqlx::{PgExecutor, Postgres, Transaction};
+use tracing::{info, warn, Instrument};
+use unicode_normalization::UnicodeNormalization;
+use url::Url;
+
+use crate::aliases;
+use crate::error::{Error, Result};
+use crate::telemetry;
+use crate::ServerState;
+
+/// Paths that are (or may become) routes of their own, so they can never be link ids.
+pub const RESERVED: &[&str] = &[
+    "api", "create", "tracking", "oembed", "qr", "static", "metrics", "healthz", "readyz",
+];
+
+/// How many generated ids are tried before giving up, in case custom ids have taken them.
+const MAX_ATTEMPTS: usize = 16;
+
+const DEFAULT_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DEFAULT_LENGTH: usize = 8;
+/// Random and hash ids are at most this long. A SHA-256 digest runs out after about 45
+/// characters of the default alphabet, so longer hash ids would only be padded.
+const MAX_LENGTH: usize = 48;
+
+/// The characters that never need escaping in a URL path.
+const UNRESERVED_PUNCTUATION: &str = "-._~";
+
+pub fn is_reserved(id: &str) -> bool {
+    RESERVED.iter().any(|name| name.eq_ignore_ascii_case(id))
+}
+
+/// How ids are generated for links that don't ask for a custom one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Sqids of the link's `index`. Short, but anyone can enumerate them.
+    Sequential,
+    /// Random characters from the alphabet.
+    Random,
+    /// Memorable ids like `brave-otter-42`.
+    Words,
+    /// Derived from a SHA-256 of the destination, so the same URL always gets the same id.
+    Hash,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 4] = [
+        Strategy::Sequential,
+        Strategy::Random,
+        Strategy::Words,
+        Strategy::Hash,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sequential => "sequential",
+            Self::Random => "random",
+            Self::Words => "words",
+            Self::Hash => "hash",
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown id strategy '{s}', use one of {}.",
+                    Self::ALL.map(Strategy::name).join(", ")
+                )
+            })
+    }
+}
+
+/// Generates ids with the instance's default strategy, or one picked per request.
+#[derive(Debug, Clone)]
+pub struct IdGenerator {
+    pub default_strategy: Strategy,
+    sqids: Sqids,
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl IdGenerator {
+    /// Reads `CHELA_ID_STRATEGY`, `CHELA_ALPHABET`, `CHELA_ID_MIN_LENGTH` (for sequential
+    /// ids) and `CHELA_ID_LENGTH` (for random and hash ids).
+    pub fn from_env() -> eyre::Result<Self> {
+        let default_strategy = match env::var("CHELA_ID_STRATEGY") {
+            Ok(strategy) if !strategy.is_empty() => strategy
+                .parse::<Strategy>()
+                .map_err(|err| eyre!("CHELA_ID_STRATEGY: {}", err))?,
+            _ => Strategy::Sequential,
+        };
+        let alphabet: Vec<char> = env::var("CHELA_ALPHABET")
+            .unwrap_or(DEFAULT_ALPHABET.to_string())
+            .chars()
+            .collect();
+        let min_length = match env::var("CHELA_ID_MIN_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => 0,
+        };
+        let length = match env::var("CHELA_ID_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => DEFAULT_LENGTH,
+        };
+        if !(1..=MAX_LENGTH).contains(&length) {
+            return Err(eyre!(
+                "CHELA_ID_LENGTH must be between 1 and {}",
+                MAX_LENGTH
+            ));
+        }
+
+        let sqids = Sqids::builder()
+            .alphabet(alphabet.clone())
+            .min_length(min_length)
+            .blocklist(RESERVED.iter().map(|name| name.to_string()).collect())
+            .build()?;
+        Ok(Self {
+            default_strategy,
+            sqids,
+            alphabet,
+            length,
+        })
+    }
+
+    fn sequential(&self, index: i64) -> Result<String> {
+        Ok(self
+            .sqids
+            .encode(&[u64::try_from(index).map_err(|err| eyre!(err))?])
+            .map_err(|err| eyre!(err))?)
+    }
+
+    fn random(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..self.length)
+            .map(|_| self.alphabet[rng.gen_range(0..self.alphabet.len())])
+            .collect()
+    }
+
+    fn words(&self) -> String {
+        let mut rng = rand::thread_rng();
+        format!(
+            "{}-{}-{}",
+            ADJECTIVES.choose(&mut rng).unwrap_or(&"brave"),
+            ANIMALS.choose(&mut rng).unwrap_or(&"otter"),
+            rng.gen_range(0..100)
+        )
+    }
+
+    /// Hashes the canonical URL, so that URLs which are deduplicated also share an id. Every
+    /// retry takes one more character of the digest, so a URL's candidates are always the
+    /// same.
+    fn hash(&self, canonical_url: &str, attempt: usize) -> String {
+        let mut digest = Sha256::digest(canonical_url.as_bytes()).to_vec();
+        let base = self.alphabet.len() as u32;
+        let length = (self.length + attempt).min(MAX_LENGTH);
+        (0..length)
+            .map(|_| {
+                // Long division of the digest by the alphabet size, one digit at a time.
+                let mut remainder = 0u32;
+                for byte in digest.iter_mut() {
+                    let value = (remainder << 8) | u32::from(*byte);
+                    *byte = (value / base) as u8;
+                    remainder = value % base;
+                }
+                self.alphabet[remainder as usize]
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Charset {
+    /// ASCII letters and digits plus `-._~`.
+    Unreserved,
+    /// Letters and digits in any script plus `-._~`.
+    Unicode,
+    Only(Vec<char>),
+}
+
+impl Charset {
+    fn allows(&self, c: char) -> bool {
+        match self {
+            Self::Unreserved => c.is_ascii_alphanumeric() || UNRESERVED_PUNCTUATION.contains(c),
+            Self::Unicode => c.is_alphanumeric() || UNRESERVED_PUNCTUATION.contains(c),
+            Self::Only(chars) => chars.contains(&c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Normalization {
+    None,
+    Nfc,
+    Nfkc,
+}
+
+/// What custom ids may look like, and whether ids are matched regardless of case.
+#[derive(Debug, Clone)]
+pub struct IdRules {
+    pub case_insensitive: bool,
+    charset: Charset,
+    min_length: usize,
+    max_length: usize,
+    normalization: Normalization,
+    blocked_words: Vec<String>,
+}
+
+impl IdRules {
+    /// Reads `CHELA_CUSTOM_ID_CHARS`, `CHELA_CUSTOM_ID_MIN_LENGTH`,
+    /// `CHELA_CUSTOM_ID_MAX_LENGTH`, `CHELA_CUSTOM_ID_NORMALIZATION`,
+    /// `CHELA_CUSTOM_ID_BLOCKLIST`, `CHELA_CUSTOM_ID_BLOCKLIST_FILE` and
+    /// `CHELA_CASE_INSENSITIVE_IDS`.
+    pub fn from_env() -> eyre::Result<Self> {
+        let charset = match env::var("CHELA_CUSTOM_ID_CHARS") {
+            Ok(chars) if chars.eq_ignore_ascii_case("unicode") => Charset::Unicode,
+            Ok(chars) if !chars.is_empty() => Charset::Only(chars.chars().collect()),
+            _ => Charset::Unreserved,
+        };
+        let min_length = match env::var("CHELA_CUSTOM_ID_MIN_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => 1,
+        };
+        let max_length = match env::var("CHELA_CUSTOM_ID_MAX_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => 64,
+        };
+        if min_length == 0 || min_length > max_length {
+            return Err(eyre!(
+                "CHELA_CUSTOM_ID_MIN_LENGTH must be at least 1 and at most CHELA_CUSTOM_ID_MAX_LENGTH"
+            ));
+        }
+        let normalization = match env::var("CHELA_CUSTOM_ID_NORMALIZATION")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "nfc" => Normalization::Nfc,
+            "nfkc" => Normalization::Nfkc,
+            "none" => Normalization::None,
+            other => {
+                return Err(eyre!(
+                    "CHELA_CUSTOM_ID_NORMALIZATION must be nfc, nfkc or none, not '{}'",
+                    other
+                ))
+            }
+        };
+
+        let mut blocked_words = env::var("CHELA_CUSTOM_ID_BLOCKLIST").unwrap_or_default();
+        if let Ok(path) = env::var("CHELA_CUSTOM_ID_BLOCKLIST_FILE") {
+            let words = std::fs::read_to_string(&path)
+                .map_err(|err| eyre!("failed to read custom id blocklist {}: {}", path, err))?;
+            blocked_words.push('\n');
+            blocked_words.push_str(&words);
+        }
+        let blocked_words = blocked_words
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+
+        let case_insensitive = env::var("CHELA_CASE_INSENSITIVE_IDS")
+            .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "yes" | "on"))
+            .unwrap_or(false);
+
+        Ok(Self {
+            case_insensitive,
+            charset,
+            min_length,
+            max_length,
+            normalization,
+            blocked_words,
+        })
+    }
+
+    /// Checks a custom id and returns it normalized, or why it can't be used.
+    pub fn check(&self, id: &str) -> eyre::Result<String> {
+        let id: String = match self.normalization {
+            Normalization::None => id.to_string(),
+            Normalization::Nfc => id.nfc().collect(),
+            Normalization::Nfkc => id.nfkc().collect(),
+        };
+
+        if id.contains('/') {
+            return Err(eyre!("ids can't contain '/'"));
+        }
+        if id.ends_with('+') {
+            return Err(eyre!(
+                "ids can't end with '+', which shows a link's info page"
+            ));
+        }
+        if id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(eyre!("ids can't contain spaces or control characters"));
+        }
+        let length = id.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(eyre!(
+                "ids must be between {} and {} characters long",
+                self.min_length,
+                self.max_length
+            ));
+        }
+        if let Some(c) = id.chars().find(|c| !self.charset.allows(*c)) {
+            return Err(eyre!("'{}' is not allowed in ids", c));
+        }
+        if is_reserved(&id) {
+            return Err(eyre!("'{}' is reserved", id));
+        }
+        if self.is_blocked(&id) {
+            return Err(eyre!("'{}' is not allowed", id));
+        }
+        Ok(id)
+    }
+
+    /// The SQL condition comparing `column` with the id bound to `param`. With
+    /// case-insensitive ids, `FOO` matches the link that was created as `foo`.
+    pub fn sql_match(&self, column: &str, param: &str) -> String {
+        if self.case_insensitive {
+            format!("lower({column}) = lower({param})")
+        } else {
+            format!("{column} = {param}")
+        }
+    }
+
+    /// Blocked words match the whole id or one of its parts, e.g. `bad` blocks `bad` and
+    /// `very-bad` but not `badge`.
+    fn is_blocked(&self, id: &str) -> bool {
+        let id = id.to_lowercase();
+        std::iter::once(id.as_str())
+            .chain(id.split(|c: char| !c.is_alphanumeric()))
+            .any(|part| self.blocked_words.iter().any(|word| word == part))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// The destination already had a link, which is handed out again.
+    Existing,
+    Generated,
+    Custom,
+}
+
+impl Allocation {
+    /// The `kind` label of the `links_created_total` metric.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Existing => "existing",
+            Self::Generated => "generated",
+            Self::Custom => "custom",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewLink {
+    pub id: String,
+    /// Where the link leads. A link that is handed out again keeps the URL it was created
+    /// with, which can differ from the requested one, e.g. in tracking parameters.
+    pub url: String,
+    pub allocation: Allocation,
+}
+
+/// Stores a link to `url` on `domain`, under `custom_id` if it is not empty and under an id
+/// generated with `strategy` otherwise.
+pub async fn allocate(
+    state: &ServerState,
+    domain: &str,
+    url: &Url,
+    custom_id: &str,
+    strategy: Strategy,
+) -> Result<NewLink> {
+    if custom_id.is_empty() {
+        allocate_generated(state, domain, url, strategy).await
+    } else {
+        allocate_custom(state, domain, url, custom_id).await
+    }
+}
+
+async fn allocate_custom(
+    state: &ServerState,
+    domain: &str,
+    url: &Url,
+    id: &str,
+) -> Result<NewLink> {
+    let id = &state
+        .id_rules
+        .check(id)
+        .map_err(|err| Error::Validation(format!("Invalid id: {err}")))?;
+    let canonical_url = state.canonicalizer.canonicalize(url);
+
+    let mut tx = state.db_pool.begin().await?;
+    let index = next_index(&mut tx).await?;
+    if claim_alias(&mut *tx, domain, id, index).await? {
+        insert_link(&mut tx, index, domain, id, url, &canonical_url, true).await?;
+        tx.commit().await?;
+        return Ok(NewLink {
+            id: id.to_string(),
+            url: url.to_string(),
+            allocation: Allocation::Custom,
+        });
+    }
+    tx.rollback().await?;
+
+    // Asking for the same id and destination again is not a conflict.
+    let existing = aliases::find_link(state, domain, id).await?;
+    match existing {
+        Some(found) if found.link.canonical_url.as_deref() == Some(canonical_url.as_str()) => {
+            Ok(NewLink {
+                id: found.alias,
+                url: found.link.url,
+                allocation: Allocation::Existing,
+            })
+        }
+        _ => {
+            state.metrics.link_conflicts.inc();
+            Err(Error::Conflict(format!("id '{id}' is already taken")))
+        }
+    }
+}
+
+async fn allocate_generated(
+    state: &ServerState,
+    domain: &str,
+    url: &Url,
+    strategy: Strategy,
+) -> Result<NewLink> {
+    let canonical_url = state.canonicalizer.canonicalize(url);
+    let mut tx = state.db_pool.begin().await?;
+
+    // Serializes creation of links to the same destination, so that concurrent requests
+    // share one id instead of both missing the lookup below.
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1 || ' ' || $2))")
+        .bind(domain)
+        .bind(&canonical_url)
+        .execute(&mut *tx)
+        .instrument(telemetry::db_span("SELECT", "pg_advisory_xact_lock"))
+        .await?;
+    // Links that send some visitors elsewhere, like split or targeted ones, aren't handed
+    // out again, and neither are ones that aren't active yet or will stop being active.
+    let existing: Option<(String, String)> = sqlx::query_as(
+        "
+SELECT id, url FROM chela.urls
+WHERE domain = $1 AND canonical_url = $2 AND custom_id = 'false' AND NOT disabled
+AND NOT EXISTS (SELECT 1 FROM chela.variants WHERE link = urls.index)
+AND NOT EXISTS (SELECT 1 FROM chela.rules WHERE link = urls.index)
+AND (active_from IS NULL OR active_from <= now()) AND active_until IS NULL
+        ",
+    )
+    .bind(domain)
+    .bind(&canonical_url)
+    .fetch_optional(&mut *tx)
+    .instrument(telemetry::db_span("SELECT", "chela.urls"))
+    .await?;
+    if let Some((id, url)) = existing {
+        return Ok(NewLink {
+            id,
+            url,
+            allocation: Allocation::Existing,
+        });
+    }
+
+    for attempt in 0..MAX_ATTEMPTS {
+        let index = next_index(&mut tx).await?;
+        let id = match strategy {
+            Strategy::Sequential => state.ids.sequential(index)?,
+            Strategy::Random => state.ids.random(),
+            Strategy::Words => state.ids.words(),
+            Strategy::Hash => state.ids.hash(&canonical_url, attempt),
+        };
+        if is_reserved(&id) {
+            continue;
+        }
+
+        if claim_alias(&mut *tx, domain, &id, index).await? {
+            insert_link(&mut tx, index, domain, &id, url, &canonical_url, false).await?;
+            tx.commit().await?;
+            return Ok(NewLink {
+                id,
+                url: url.to_string(),
+                allocation: Allocation::Generated,
+            });
+        }
+
+        info!(
+            "Generated id '{}' is already taken on {}, retrying",
+            id, domain
+        );
+        state.metrics.link_conflicts.inc();
+    }
+
+    warn!(
+        "No free id found on {} after {} attempts",
+        domain, MAX_ATTEMPTS
+    );
+    Err(eyre!("no free id found after {} attempts", MAX_ATTEMPTS).into())
+}
+
+const ADJECTIVES: &[&str] = &[
+    "agile", "amber", "bold", "brave", "breezy", "bright", "calm", "clever", "cosy", "crisp",
+    "curious", "daring", "dusty", "eager", "fancy", "fuzzy", "gentle", "giddy", "glad", "golden",
+    "grand", "happy", "hardy", "hasty", "honest", "humble", "jolly", "keen", "kind", "lively",
+    "lucky", "mellow", "merry", "mighty", "misty", "nimble", "noble", "plucky", "polite", "proud",
+    "quick", "quiet", "rapid", "rosy", "rustic", "shiny", "silent", "silver", "sleepy", "snowy",
+    "solid", "spry", "steady", "sunny", "swift", "tidy", "tiny", "velvet", "vivid", "warm", "wild",
+    "windy", "witty", "zesty",
+];
+
+const ANIMALS: &[&str] = &[
+    "alpaca", "badger", "beaver", "bison", "bobcat", "camel", "cheetah", "condor", "cougar",
+    "coyote", "crane", "dingo", "dolphin", "donkey", "eagle", "falcon", "ferret", "finch", "gecko",
+    "gazelle", "gibbon", "heron", "hippo", "ibex", "iguana", "jackal", "jaguar", "koala", "lemur",
+    "leopard", "llama", "lynx", "magpie", "marmot", "meerkat", "moose", "narwhal", "ocelot",
+    "osprey", "otter", "owl", "panda", "panther", "parrot", "pelican", "penguin", "puffin",
+    "quail", "rabbit", "raccoon", "raven", "salmon", "seal", "sparrow", "squid", "stork", "tapir",
+    "tiger", "toucan", "turtle", "walrus", "weasel", "wombat", "yak",
+];
+
+async fn next_index(tx: &mut Transaction<'_, Postgres>) -> Result<i64> {
+    let (index,): (i64,) =
+        sqlx::query_as("SELECT nextval(pg_get_serial_sequence('chela.urls', 'index'))")
+            .fetch_one(&mut **tx)
+            .instrument(telemetry::db_span("SELECT", "chela.urls_index_seq"))
+            .await?;
+    Ok(index)
+}
+
+/// Makes `id` an alias of the link at `index`, unless some link already has that id.
+pub async fn claim_alias(
+    executor: impl PgExecutor<'_>,
+    domain: &str,
+    id: &str,
+    index: i64,
+) -> Result<bool> {
+    let inserted = sqlx::query(
+        "
+INSERT INTO chela.aliases (domain,id,link)
+VALUES ($1,$2,$3)
+ON CONFLICT DO NOTHING
+        ",
+    )
+    .bind(domain)
+    .bind(id)
+    .bind(index)
+    .execute(executor)
+    .instrument(telemetry::db_span("INSERT", "chela.aliases"))
+    .await?;
+    Ok(inserted.rows_affected() == 1)
+}
+
+async fn insert_link(
+    tx: &mut Transaction<'_, Postgres>,
+    index: i64,
+    domain: &str,
+    id: &str,
+    url: &Url,
+    canonical_url: &str,
+    custom_id: bool,
+) -> Result<()> {
+    sqlx::query(
+        "
+INSERT INTO chela.urls (index,domain,id,url,canonical_url,custom_id)
+VALUES ($1,$2,$3,$4,$5,$6)
+        ",
+    )
+    .bind(index)
+    .bind(domain)
+    .bind(id)
+    .bind(url.as_str())
+    .bind(canonical_url)
+    .bind(custom_id)
+    .execute(&mut **tx)
+    .instrument(telemetry::db_span("INSERT", "chela.urls"))
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{allocate, Allocation, Strategy};
+    use crate::ServerState;
+
+    /// Creates a link, runs `statement` with the link's index as `$1`, and returns whether
+    /// another link to the same destination is handed the same id.
+    async fn reused_after(state: &ServerState, statement: &str) -> bool {
+        let domain = ServerState::test_domain();
+        let url = Url::parse("https://example.com/").unwrap();
+        let created = allocate(state, &domain, &url, "", Strategy::Random)
+            .await
+            .unwrap();
+        let (index,): (i64,) =
+            sqlx::query_as("SELECT link FROM chela.aliases WHERE domain = $1 AND id = $2")
+                .bind(&domain)
+                .bind(&created.id)
+                .fetch_one(&state.db_pool)
+                .await
+                .unwrap();
+        sqlx::query(statement)
+            .bind(index)
+            .execute(&state.db_pool)
+            .await
+            .unwrap();
+
+        let again = allocate(state, &domain, &url, "", Strategy::Random)
+            .await
+            .unwrap();
+        assert_eq!(
+            again.id == created.id,
+            again.allocation == Allocation::Existing
+        );
+        again.allocation == Allocation::Existing
+    }
+
+    #[tokio::test]
+    async fn hands_out_existing_links_with_the_url_they_were_stored_with() {
+        let Some(state) = ServerState::for_tests().await else {
+            return;
+        };
+        let domain = ServerState::test_domain();
+        let stored = Url::parse("https://example.com/page?b=2&a=1").unwrap();
+        let tracked = Url::parse("https://example.com/page?b=2&utm_source=news&a=1").unwrap();
+
+        let created = allocate(&state, &domain, &stored, "", Strategy::Random)
+            .await
+            .unwrap();
+        assert_eq!(created.allocation, Allocation::Generated);
+        let reused = allocate(&state, &domain, &tracked, "", Strategy::Random)
+            .await
+            .unwrap();
+        assert_eq!(reused.allocation, Allocation::Existing);
+        assert_eq!(reused.id, created.id);
+        assert_eq!(reused.url, stored.as_str());
+
+        let created = allocate(&state, &domain, &stored, "page", Strategy::Random)
+            .await
+            .unwrap();
+        assert_eq!(created.allocation, Allocation::Custom);
+        let reused = allocate(&state, &domain, &tracked, "page", Strategy::Random)
+            .await
+            .unwrap();
+        assert_eq!(reused.allocation, Allocation::Existing);
+        assert_eq!(reused.id, "page");
+        assert_eq!(reused.url, stored.as_str());
+    }
+
+    #[tokio::test]
+    async fn does_not_hand_out_split_links() {
+        let Some(state) = ServerState::for_tests().await else {
+            return;
+        };
+        assert!(reused_after(&state, "SELECT $1").await);
+        assert!(
+            !reused_after(
+                &state,
+                "INSERT INTO chela.variants (link,name,url,weight) VALUES ($1,'B','https://example.org/',1)",
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_hand_out_targeted_links() {
+        let Some(state) = ServerState::for_tests().await else {
+            return;
+        };
+        assert!(
+            !reused_after(
+                &state,
+                "INSERT INTO chela.rules (link,position,field,matches,url) VALUES ($1,1,'device','{mobile}','https://example.org/')",
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_hand_out_scheduled_links() {
+        let Some(state) = ServerState::for_tests().await else {
+            return;
+        };
+        assert!(
+            reused_after(
+                &state,
+                "UPDATE chela.urls SET active_from = now() - interval '1 hour' WHERE index = $1",
+            )
+            .await
+        );
+        assert!(
+            !reused_after(
+                &state,
+                "UPDATE chela.urls SET active_from = now() + interval '1 hour' WHERE index = $1",
+            )
+            .await
+        );
+        assert!(
+            !reused_after(
+                &state,
+                "UPDATE chela.urls SET active_until = now() + interval '1 hour' WHERE index = $1",
+            )
+            .await
+        );
+    }
+}
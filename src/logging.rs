@@ -0,0 +1,120 @@
+use std::env;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use tracing::{field, info, Instrument, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::proxy::ClientInfo;
+use crate::telemetry;
+use crate::ServerState;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Installs the global subscriber. `CHELA_LOG` (or `RUST_LOG`) sets the filter,
+/// `CHELA_LOG_FORMAT=json` switches to one JSON object per line, and spans are exported
+/// over OTLP when `CHELA_OTLP_ENDPOINT` is set.
+pub fn init() -> eyre::Result<()> {
+    let filter = env::var("CHELA_LOG")
+        .or_else(|_| env::var("RUST_LOG"))
+        .unwrap_or("info".to_string());
+
+    let fmt_layer = if env::var("CHELA_LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")) {
+        tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer().with_target(false).boxed()
+    };
+    let otel_layer = telemetry::tracer_from_env()?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::new(filter))
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+    Ok(())
+}
+
+/// Wraps every request in a span carrying its method, path, client IP and request id, and
+/// logs the status and latency once it finishes. Handlers can fill in `link_id`.
+pub async fn request_span(
+    Extension(state): Extension<ServerState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|it| it.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let client = ClientInfo::resolve(
+        request.headers(),
+        request.uri(),
+        request.extensions(),
+        &state,
+    );
+
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), |p| p.as_str())
+        .to_string();
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        client_ip = %client.ip,
+        host = %client.host,
+        link_id = field::Empty,
+        status = field::Empty,
+        latency_ms = field::Empty,
+        otel.name = %format!("{} {}", request.method(), route),
+        otel.kind = "server",
+    );
+    telemetry::set_parent_from_headers(&span, request.headers());
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        request.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", latency_ms);
+    span.in_scope(|| info!("Finished request"));
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Records the link id on the current request span.
+pub fn record_link_id(id: &str) {
+    Span::current().record("link_id", id);
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
@@ -1,5 +1,6 @@
 use axum::extract::connect_info;
 use axum::http::Request;
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::Router;
 
@@ -10,49 +11,196 @@ use hyper::body::Incoming;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server;
 
-use info_utils::prelude::*;
 use serde::Deserialize;
-use sqids::Sqids;
 use tower::Service;
-use url::Url;
+use tracing::{info, warn};
 
+use std::convert::Infallible;
 use std::env;
 use std::sync::Arc;
 
+pub mod aliases;
+pub mod canonical;
+pub mod cards;
+pub mod csrf;
+pub mod domains;
+pub mod error;
 pub mod get;
+pub mod health;
+pub mod ids;
+pub mod logging;
+pub mod metrics;
+pub mod policy;
 pub mod post;
+pub mod preview;
+pub mod proxy;
+pub mod qr;
+pub mod ratelimit;
+pub mod schedule;
+pub mod screening;
+pub mod targeting;
+pub mod telemetry;
+pub mod templates;
+pub mod tls;
+pub mod variants;
 
 #[derive(Clone)]
 pub struct ServerState {
     pub db_pool: Pool<Postgres>,
     pub host: String,
-    pub sqids: Sqids,
-    pub main_page_redirect: Option<Url>,
-    pub behind_proxy: bool,
+    pub ids: ids::IdGenerator,
+    pub id_rules: ids::IdRules,
+    pub canonicalizer: canonical::Canonicalizer,
+    pub domains: domains::Domains,
+    pub proxies: proxy::TrustedProxies,
     pub uses_https: bool,
+    pub url_policy: policy::UrlPolicy,
+    pub screener: screening::Screener,
+    pub rate_limits: ratelimit::RateLimits,
+    pub metrics: metrics::Metrics,
+    pub previewer: preview::Previewer,
+    pub unfurl_bots: cards::UnfurlBots,
+    pub geoip: targeting::GeoIp,
+    pub schedule: schedule::Schedule,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, sqlx::FromRow, PartialEq, Eq)]
 pub struct UrlRow {
     pub index: i64,
+    pub domain: String,
     pub id: String,
     pub url: String,
+    pub canonical_url: Option<String>,
     pub custom_id: bool,
+    pub disabled: bool,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub title: Option<String>,
+    pub title_fetched_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub og_title: Option<String>,
+    pub og_description: Option<String>,
+    pub og_image: Option<String>,
+    pub weight: i32,
+    pub active_from: Option<chrono::DateTime<chrono::Utc>>,
+    pub active_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl UrlRow {
+    /// Whether the link leads anywhere right now: `active`, `disabled`, `scheduled` or
+    /// `expired`.
+    pub fn status(&self) -> &'static str {
+        if self.disabled {
+            return "disabled";
+        }
+        match schedule::availability(self, chrono::Utc::now()) {
+            schedule::Availability::Pending => "scheduled",
+            schedule::Availability::Active => "active",
+            schedule::Availability::Expired => "expired",
+        }
+    }
+}
+
+/// One of the ids that lead to a link.
+#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
+pub struct AliasRow {
+    pub domain: String,
+    pub id: String,
+    pub link: i64,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Another destination of a link, which visitors are split across by weight.
+#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
+pub struct VariantRow {
+    pub link: i64,
+    pub name: String,
+    pub url: String,
+    pub weight: i32,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Sends the visitors of a link whose country, device, language or operating system, or the
+/// day or time of whose visit, is one of `matches` somewhere else.
+#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
+pub struct RuleRow {
+    pub id: i64,
+    pub link: i64,
+    pub position: i32,
+    pub field: String,
+    pub matches: Vec<String>,
+    pub url: String,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A link together with how often it has been visited.
+#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
+pub struct LinkStats {
+    #[sqlx(flatten)]
+    pub link: UrlRow,
+    pub clicks: i64,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
 pub struct TrackingRow {
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub domain: String,
     pub id: String,
     pub ip: Option<String>,
     pub referrer: Option<String>,
     pub user_agent: Option<String>,
+    pub variant: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct CreateForm {
     pub id: String,
     pub url: url::Url,
+    pub domain: Option<String>,
+    pub strategy: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EditForm {
+    pub url: url::Url,
+    #[serde(default)]
+    pub og_title: String,
+    #[serde(default)]
+    pub og_description: String,
+    #[serde(default)]
+    pub og_image: String,
+    #[serde(default)]
+    pub weight: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AliasForm {
+    pub alias: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VariantForm {
+    pub url: url::Url,
+    #[serde(default)]
+    pub weight: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RuleForm {
+    pub field: String,
+    pub matches: String,
+    pub url: url::Url,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleForm {
+    #[serde(default)]
+    pub active_from: String,
+    #[serde(default)]
+    pub active_until: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DisableForm {
+    pub disabled: bool,
 }
 
 #[derive(Clone)]
@@ -77,66 +225,120 @@ impl connect_info::Connected<&tokio::net::UnixStream> for UdsConnectInfo {
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
+    logging::init()?;
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("healthcheck") {
+        return health::run_healthcheck(args.iter().any(|a| a == "--ready")).await;
+    }
 
-    let db_pool = init_db().await?;
     let host = env::var("CHELA_HOST").unwrap_or("localhost".to_string());
-    let alphabet = env::var("CHELA_ALPHABET")
-        .unwrap_or("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ".to_string());
-    let sqids = Sqids::builder()
-        .alphabet(alphabet.chars().collect())
-        .blocklist(["create".to_string(), "tracking".to_string()].into())
-        .build()?;
-    let main_page_redirect = env::var("CHELA_MAIN_PAGE_REDIRECT").unwrap_or_default();
+    let domains = domains::Domains::from_env(&host)?;
+    let id_rules = ids::IdRules::from_env()?;
+    let canonicalizer = canonical::Canonicalizer::from_env()?;
+    let db_pool = init_db(&domains, &id_rules).await?;
+    canonical::backfill(&db_pool, &canonicalizer).await?;
+    let metrics = metrics::Metrics::new(db_pool.options().get_max_connections())?;
     let behind_proxy = env::var("CHELA_BEHIND_PROXY").is_ok();
-    let uses_https = env::var("CHELA_USES_HTTPS").is_ok();
+    let proxies = proxy::TrustedProxies::from_env(behind_proxy)?;
+    let tls_config = tls::TlsConfig::from_env()?;
+    let uses_https = env::var("CHELA_USES_HTTPS").is_ok() || tls_config.is_some();
+    let screener = screening::Screener::from_env();
+    let blocklist_refresh = env::var("CHELA_BLOCKLIST_REFRESH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(60);
+    screener.spawn_refresh(std::time::Duration::from_secs(blocklist_refresh));
     let server_state = ServerState {
         db_pool,
         host,
-        sqids,
-        main_page_redirect: Url::parse(&main_page_redirect).ok(),
-        behind_proxy,
-        uses_https
+        ids: ids::IdGenerator::from_env()?,
+        id_rules,
+        canonicalizer,
+        domains,
+        proxies,
+        uses_https,
+        url_policy: policy::UrlPolicy::from_env(),
+        screener,
+        rate_limits: ratelimit::RateLimits::from_env()?,
+        metrics,
+        previewer: preview::Previewer::from_env()?,
+        unfurl_bots: cards::UnfurlBots::from_env(),
+        geoip: targeting::GeoIp::from_env()?,
+        schedule: schedule::Schedule::from_env()?,
     };
 
-    serve(server_state).await?;
-    Ok(())
+    let result = serve(server_state, tls_config).await;
+    telemetry::shutdown();
+    result
 }
 
-async fn serve(state: ServerState) -> eyre::Result<()> {
+async fn serve(state: ServerState, tls: Option<tls::TlsConfig>) -> eyre::Result<()> {
+    let router = Router::new()
+        .route("/", get(get::index))
+        .route("/create", get(get::create_id))
+        .route("/tracking", get(get::tracking))
+        .route("/tracking/:id", get(get::tracking_id))
+        .route("/tracking/:id/edit", post(post::update_link))
+        .route("/tracking/:id/disable", post(post::set_disabled))
+        .route("/tracking/:id/schedule", post(post::set_schedule))
+        .route("/tracking/:id/delete", post(post::delete_link))
+        .route("/tracking/:id/aliases", post(post::add_alias))
+        .route(
+            "/tracking/:id/aliases/:alias/delete",
+            post(post::remove_alias),
+        )
+        .route("/tracking/:id/variants", post(post::add_variant))
+        .route(
+            "/tracking/:id/variants/:name/delete",
+            post(post::remove_variant),
+        )
+        .route("/tracking/:id/rules", post(post::add_rule))
+        .route("/tracking/:id/rules/:rule/up", post(post::move_rule_up))
+        .route("/tracking/:id/rules/:rule/delete", post(post::remove_rule))
+        .route(
+            "/qr/:id",
+            get(qr::qr).layer(middleware::from_fn(ratelimit::limit_redirect)),
+        )
+        .route("/oembed", get(cards::oembed))
+        .route("/static/chela.css", get(templates::stylesheet))
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route(
+            "/:id",
+            get(get::id).layer(middleware::from_fn(ratelimit::limit_redirect)),
+        )
+        .route(
+            "/",
+            post(post::create_link).layer(middleware::from_fn(ratelimit::limit_create)),
+        )
+        .layer(middleware::from_fn(error::negotiate))
+        .layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(logging::request_span))
+        .layer(axum::Extension(state.clone()));
+
     let unix_socket = env::var("CHELA_UNIX_SOCKET").unwrap_or_default();
     if unix_socket.is_empty() {
-        let router = Router::new()
-            .route("/", get(get::index))
-            .route("/create", get(get::create_id))
-            .route("/tracking", get(get::tracking))
-            .route("/tracking/:id", get(get::tracking_id))
-            .route("/:id", get(get::id))
-            .route("/", post(post::create_link))
-            .layer(axum::Extension(state));
         let address = env::var("CHELA_LISTEN_ADDRESS").unwrap_or("0.0.0.0".to_string());
         let port = 3000;
+        if let Some(tls_config) = tls {
+            return tls::serve(router, state, &address, port, tls_config).await;
+        }
         let listener = tokio::net::TcpListener::bind(format!("{address}:{port}")).await?;
-        log!("Listening at {}:{}", address, port);
+        info!("Listening at {}:{}", address, port);
         axum::serve(
             listener,
             router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
         )
         .await?;
     } else {
-        let router = Router::new()
-            .route("/", get(get::index))
-            .route("/create", get(get::create_id))
-            .route("/tracking", get(get::tracking))
-            .route("/tracking/:id", get(get::tracking_id))
-            .route("/:id", get(get::id_unix))
-            .route("/", post(post::create_link))
-            .layer(axum::Extension(state));
         let unix_socket_path = std::path::Path::new(&unix_socket);
         if unix_socket_path.exists() {
             tokio::fs::remove_file(unix_socket_path).await?;
         }
         let listener = tokio::net::UnixListener::bind(unix_socket_path)?;
-        log!("Listening via Unix socket at {}", unix_socket);
+        info!("Listening via Unix socket at {}", unix_socket);
         tokio::spawn(async move {
             let mut service = router.into_make_service_with_connect_info::<UdsConnectInfo>();
             loop {
@@ -146,20 +348,7 @@ async fn serve(state: ServerState) -> eyre::Result<()> {
                     Err(err) => match err {},
                 };
 
-                tokio::spawn(async move {
-                    let socket = TokioIo::new(socket);
-                    let hyper_service =
-                        hyper::service::service_fn(move |request: Request<Incoming>| {
-                            tower_service.clone().call(request)
-                        });
-
-                    if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection_with_upgrades(socket, hyper_service)
-                        .await
-                    {
-                        warn!("Failed to serve connection: {}", err);
-                    }
-                });
+                spawn_connection(socket, tower_service);
             }
         })
         .await?;
@@ -168,7 +357,35 @@ async fn serve(state: ServerState) -> eyre::Result<()> {
     Ok(())
 }
 
-async fn init_db() -> eyre::Result<Pool<Postgres>> {
+/// Serves a single HTTP/1 or HTTP/2 connection on its own task.
+pub fn spawn_connection<I, S>(io: I, tower_service: S)
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = axum::response::Response, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    tokio::spawn(async move {
+        let socket = TokioIo::new(io);
+        let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+            tower_service.clone().call(request)
+        });
+
+        if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(socket, hyper_service)
+            .await
+        {
+            warn!("Failed to serve connection: {}", err);
+        }
+    });
+}
+
+async fn init_db(
+    domains: &domains::Domains,
+    id_rules: &ids::IdRules,
+) -> eyre::Result<Pool<Postgres>> {
     let db_pool = PgPoolOptions::new()
         .max_connections(15)
         .connect(
@@ -177,41 +394,268 @@ async fn init_db() -> eyre::Result<Pool<Postgres>> {
                 .as_str(),
         )
         .await?;
-    log!("Successfully connected to database");
+    info!("Successfully connected to database");
 
     sqlx::query("CREATE SCHEMA IF NOT EXISTS chela")
         .execute(&db_pool)
         .await?;
-    log!("Created schema chela");
+    info!("Created schema chela");
 
     sqlx::query(
         "
 CREATE TABLE IF NOT EXISTS chela.urls (
     index BIGSERIAL PRIMARY KEY,
-    id TEXT NOT NULL UNIQUE,
+    domain TEXT NOT NULL,
+    id TEXT NOT NULL,
     url TEXT NOT NULL,
-    custom_id BOOLEAN NOT NULL
+    canonical_url TEXT,
+    custom_id BOOLEAN NOT NULL,
+    disabled BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    title TEXT,
+    title_fetched_at TIMESTAMPTZ,
+    og_title TEXT,
+    og_description TEXT,
+    og_image TEXT,
+    weight INTEGER NOT NULL DEFAULT 1,
+    active_from TIMESTAMPTZ,
+    active_until TIMESTAMPTZ,
+    UNIQUE (domain, id)
 )
         ",
     )
     .execute(&db_pool)
     .await?;
-    log!("Created table chela.urls");
+    info!("Created table chela.urls");
 
     sqlx::query(
         "
 CREATE TABLE IF NOT EXISTS chela.tracking (
     timestamp TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    domain TEXT NOT NULL,
     id TEXT NOT NULL,
     ip TEXT,
     referrer TEXT,
-    user_agent TEXT
+    user_agent TEXT,
+    variant TEXT
 )
         ",
     )
     .execute(&db_pool)
     .await?;
-    log!("Created table chela.tracking");
+    info!("Created table chela.tracking");
+
+    // Tables created before multi-domain support have no domain column, and ids were
+    // unique across the whole instance. Assign their links to the primary domain.
+    for table in ["urls", "tracking"] {
+        sqlx::query(&format!(
+            "ALTER TABLE chela.{table} ADD COLUMN IF NOT EXISTS domain TEXT NOT NULL DEFAULT ''"
+        ))
+        .execute(&db_pool)
+        .await?;
+        sqlx::query(&format!(
+            "UPDATE chela.{table} SET domain = $1 WHERE domain = ''"
+        ))
+        .bind(&domains.primary().name)
+        .execute(&db_pool)
+        .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE chela.{table} ALTER COLUMN domain DROP DEFAULT"
+        ))
+        .execute(&db_pool)
+        .await?;
+    }
+    sqlx::query("ALTER TABLE chela.urls DROP CONSTRAINT IF EXISTS urls_id_key")
+        .execute(&db_pool)
+        .await?;
+    sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS urls_domain_id_key ON chela.urls (domain, id)")
+        .execute(&db_pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS tracking_domain_id ON chela.tracking (domain, id)")
+        .execute(&db_pool)
+        .await?;
+    info!("Migrated tables to per-domain ids");
+
+    sqlx::query(
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT false",
+    )
+    .execute(&db_pool)
+    .await?;
+
+    // Links created before creation dates were recorded keep a NULL `created_at`.
+    for statement in [
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ",
+        "ALTER TABLE chela.urls ALTER COLUMN created_at SET DEFAULT CURRENT_TIMESTAMP",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS title TEXT",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS title_fetched_at TIMESTAMPTZ",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS og_title TEXT",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS og_description TEXT",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS og_image TEXT",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS canonical_url TEXT",
+        "CREATE INDEX IF NOT EXISTS urls_domain_canonical_url ON chela.urls (domain, canonical_url)",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS weight INTEGER NOT NULL DEFAULT 1",
+        "ALTER TABLE chela.tracking ADD COLUMN IF NOT EXISTS variant TEXT",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS active_from TIMESTAMPTZ",
+        "ALTER TABLE chela.urls ADD COLUMN IF NOT EXISTS active_until TIMESTAMPTZ",
+    ] {
+        sqlx::query(statement).execute(&db_pool).await?;
+    }
+
+    // Settings that stored data was derived with, so that it can be redone when they change.
+    sqlx::query(
+        "
+CREATE TABLE IF NOT EXISTS chela.settings (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+)
+        ",
+    )
+    .execute(&db_pool)
+    .await?;
+    info!("Created table chela.settings");
+
+    // Every id that leads to a link, including the one it was created with. Ids are unique
+    // here rather than in chela.urls, so an alias can never shadow another link's id. The
+    // foreign key is deferred so that a link's first alias can be claimed before its row is
+    // inserted.
+    sqlx::query(
+        "
+CREATE TABLE IF NOT EXISTS chela.aliases (
+    domain TEXT NOT NULL,
+    id TEXT NOT NULL,
+    link BIGINT NOT NULL REFERENCES chela.urls (index) ON DELETE CASCADE DEFERRABLE INITIALLY DEFERRED,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (domain, id)
+)
+        ",
+    )
+    .execute(&db_pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS aliases_link ON chela.aliases (link)")
+        .execute(&db_pool)
+        .await?;
+    let migrated = sqlx::query(
+        "
+INSERT INTO chela.aliases (domain,id,link,created_at)
+SELECT domain, id, index, created_at FROM chela.urls
+WHERE NOT EXISTS (SELECT 1 FROM chela.aliases WHERE aliases.link = urls.index)
+ON CONFLICT DO NOTHING
+        ",
+    )
+    .execute(&db_pool)
+    .await?;
+    if migrated.rows_affected() > 0 {
+        info!(
+            "Created aliases for {} existing links",
+            migrated.rows_affected()
+        );
+    }
+    info!("Created table chela.aliases");
+
+    // Destinations besides the link's own URL, which is variant `A`. Visits to links without
+    // any record no variant.
+    sqlx::query(
+        "
+CREATE TABLE IF NOT EXISTS chela.variants (
+    link BIGINT NOT NULL REFERENCES chela.urls (index) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    url TEXT NOT NULL,
+    weight INTEGER NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (link, name)
+)
+        ",
+    )
+    .execute(&db_pool)
+    .await?;
+    info!("Created table chela.variants");
+
+    // Targeting rules are tried in order of `position` before the link's destination.
+    sqlx::query(
+        "
+CREATE TABLE IF NOT EXISTS chela.rules (
+    id BIGSERIAL PRIMARY KEY,
+    link BIGINT NOT NULL REFERENCES chela.urls (index) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    matches TEXT[] NOT NULL,
+    url TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+)
+        ",
+    )
+    .execute(&db_pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS rules_link ON chela.rules (link)")
+        .execute(&db_pool)
+        .await?;
+    info!("Created table chela.rules");
+
+    // Case-insensitive lookups use `lower(id)`, which this index keeps unique and fast.
+    sqlx::query("DROP INDEX IF EXISTS chela.urls_domain_lower_id_key")
+        .execute(&db_pool)
+        .await?;
+    if id_rules.case_insensitive {
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS aliases_domain_lower_id_key ON chela.aliases (domain, lower(id))",
+        )
+        .execute(&db_pool)
+        .await
+        .map_err(|err| {
+            eyre::eyre!(
+                "CHELA_CASE_INSENSITIVE_IDS needs ids that are unique regardless of case: {}",
+                err
+            )
+        })?;
+    } else {
+        sqlx::query("DROP INDEX IF EXISTS chela.aliases_domain_lower_id_key")
+            .execute(&db_pool)
+            .await?;
+    }
 
     Ok(db_pool)
 }
+
+#[cfg(test)]
+impl ServerState {
+    /// A state configured from the environment like in `main`, for tests that need a
+    /// database. `None` when `DATABASE_URL` isn't set, which skips those tests.
+    pub async fn for_tests() -> Option<Self> {
+        // Tests run in parallel, and creating the schema concurrently trips over itself.
+        static INIT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+        env::var("DATABASE_URL").ok()?;
+        let host = "localhost".to_string();
+        let domains = domains::Domains::from_env(&host).unwrap();
+        let id_rules = ids::IdRules::from_env().unwrap();
+        let db_pool = {
+            let _init = INIT.lock().await;
+            init_db(&domains, &id_rules).await.unwrap()
+        };
+        let metrics = metrics::Metrics::new(db_pool.options().get_max_connections()).unwrap();
+        Some(ServerState {
+            db_pool,
+            host,
+            ids: ids::IdGenerator::from_env().unwrap(),
+            id_rules,
+            canonicalizer: canonical::Canonicalizer::from_env().unwrap(),
+            domains,
+            proxies: proxy::TrustedProxies::from_env(false).unwrap(),
+            uses_https: false,
+            url_policy: policy::UrlPolicy::from_env(),
+            screener: screening::Screener::from_env(),
+            rate_limits: ratelimit::RateLimits::from_env().unwrap(),
+            metrics,
+            previewer: preview::Previewer::from_env().unwrap(),
+            unfurl_bots: cards::UnfurlBots::from_env(),
+            geoip: targeting::GeoIp::from_env().unwrap(),
+            schedule: schedule::Schedule::from_env().unwrap(),
+        })
+    }
+
+    /// A domain of its own for a test, so that tests sharing a database don't see each
+    /// other's links.
+    pub fn test_domain() -> String {
+        format!("{:016x}.test", rand::random::<u64>())
+    }
+}
@@ -0,0 +1,182 @@
+use std::env;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use tracing::warn;
+
+use crate::ServerState;
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub redirects: IntCounterVec,
+    pub links_created: IntCounterVec,
+    pub link_conflicts: IntCounter,
+    pub analytics_failures: IntCounter,
+    pub pool_connections: IntGaugeVec,
+    pub pool_max_connections: IntGauge,
+    /// Required as `Authorization: Bearer <token>` to read `/metrics`, if set.
+    token: Option<String>,
+}
+
+impl Metrics {
+    /// Reads `CHELA_METRICS_TOKEN`, which `/metrics` is protected with if it is set.
+    pub fn new(max_connections: u32) -> eyre::Result<Self> {
+        let registry = Registry::new_custom(Some("chela".to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["method", "route"],
+        )?;
+        let redirects = IntCounterVec::new(
+            Opts::new("redirects_total", "Short link lookups by result"),
+            &["result"],
+        )?;
+        let links_created = IntCounterVec::new(
+            Opts::new("links_created_total", "Link creation requests by outcome"),
+            &["kind"],
+        )?;
+        let link_conflicts = IntCounter::new(
+            "link_conflicts_total",
+            "Ids that were already taken, whether custom ids that were rejected or generated ids that were retried",
+        )?;
+        let analytics_failures = IntCounter::new(
+            "analytics_insert_failures_total",
+            "Failed inserts into chela.tracking",
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections by state"),
+            &["state"],
+        )?;
+        let pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Maximum size of the database pool",
+        )?;
+        pool_max_connections.set(max_connections.into());
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(redirects.clone()))?;
+        registry.register(Box::new(links_created.clone()))?;
+        registry.register(Box::new(link_conflicts.clone()))?;
+        registry.register(Box::new(analytics_failures.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_max_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            redirects,
+            links_created,
+            link_conflicts,
+            analytics_failures,
+            pool_connections,
+            pool_max_connections,
+            token: env::var("CHELA_METRICS_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        })
+    }
+}
+
+/// Records the count and latency of every request, labelled by the matched route rather
+/// than the raw path so that link ids don't create a series each.
+pub async fn track(
+    Extension(state): Extension<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |p| p.as_str().to_string());
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    state
+        .metrics
+        .request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+pub async fn metrics(
+    headers: HeaderMap,
+    Extension(state): Extension<ServerState>,
+) -> impl IntoResponse {
+    if let Some(token) = &state.metrics.token {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|it| it.to_str().ok())
+            .and_then(|it| it.strip_prefix("Bearer "))
+            .map(str::trim)
+            .unwrap_or_default();
+        if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Unauthorized.",
+            )
+                .into_response();
+        }
+    }
+
+    let size = i64::from(state.db_pool.size());
+    let idle = i64::try_from(state.db_pool.num_idle()).unwrap_or(i64::MAX);
+    state
+        .metrics
+        .pool_connections
+        .with_label_values(&["idle"])
+        .set(idle);
+    state
+        .metrics
+        .pool_connections
+        .with_label_values(&["active"])
+        .set(size - idle);
+
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&state.metrics.registry.gather(), &mut buffer) {
+        warn!("Failed to encode metrics: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal error.").into_response();
+    }
+
+    (
+        [("Content-Type", prometheus::TEXT_FORMAT)],
+        String::from_utf8(buffer).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+/// Compares tokens without returning early, so the time taken doesn't give away how much of
+/// a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,145 @@
+use std::env;
+
+use url::Url;
+
+use crate::domains::Domains;
+
+const DEFAULT_SCHEMES: &str = "http,https";
+const DEFAULT_SHORTENERS: &str = "bit.ly,bitly.com,t.co,tinyurl.com,goo.gl,ow.ly,is.gd,buff.ly,rebrand.ly,cutt.ly,shorturl.at,t.ly,tiny.cc,rb.gy,s.id";
+
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    pub allowed_schemes: Vec<String>,
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    pub shortener_domains: Vec<String>,
+}
+
+impl UrlPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            allowed_schemes: split_list(
+                &env::var("CHELA_ALLOWED_SCHEMES").unwrap_or(DEFAULT_SCHEMES.to_string()),
+            ),
+            allowed_domains: split_list(&env::var("CHELA_ALLOWED_DOMAINS").unwrap_or_default()),
+            denied_domains: split_list(&env::var("CHELA_DENIED_DOMAINS").unwrap_or_default()),
+            shortener_domains: split_list(
+                &env::var("CHELA_SHORTENER_DOMAINS").unwrap_or(DEFAULT_SHORTENERS.to_string()),
+            ),
+        }
+    }
+
+    /// Checks a destination URL against the policy. `domains` are the domains this instance
+    /// serves, used to reject links that would redirect back to chela itself.
+    pub fn check(&self, url: &Url, domains: &Domains) -> eyre::Result<()> {
+        let scheme = url.scheme();
+        if !self.allowed_schemes.iter().any(|s| s == scheme) {
+            return Err(eyre::eyre!("scheme '{}' is not allowed", scheme));
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err(eyre::eyre!("URL must have a host"));
+        };
+        let host = host.trim_end_matches('.').to_lowercase();
+
+        if let Some(domain) = domains.find(&host) {
+            return Err(eyre::eyre!(
+                "links to {} itself are not allowed",
+                domain.name
+            ));
+        }
+
+        if self.denied_domains.iter().any(|d| domain_matches(d, &host)) {
+            return Err(eyre::eyre!("domain '{}' is not allowed", host));
+        }
+        if !self.allowed_domains.is_empty()
+            && !self
+                .allowed_domains
+                .iter()
+                .any(|d| domain_matches(d, &host))
+        {
+            return Err(eyre::eyre!("domain '{}' is not in the allowlist", host));
+        }
+        if self
+            .shortener_domains
+            .iter()
+            .any(|d| domain_matches(d, &host))
+        {
+            return Err(eyre::eyre!(
+                "links to other URL shorteners ('{}') are not allowed",
+                host
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches `host` against a domain pattern. A bare pattern like `example.com` matches the
+/// domain and any of its subdomains; `*` matches any run of characters, so `*.example.com`
+/// matches only subdomains and `cdn*.example.com` matches `cdn1.example.com`.
+pub fn domain_matches(pattern: &str, host: &str) -> bool {
+    if !pattern.contains('*') {
+        return host == pattern || host.ends_with(&format!(".{pattern}"));
+    }
+
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let mut rest = host;
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(part) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(idx) => rest = &rest[idx + part.len()..],
+                None => return false,
+            }
+        }
+    }
+
+    rest.is_empty()
+}
+
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::domain_matches;
+
+    #[test]
+    fn bare_domains_match_themselves_and_subdomains() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(domain_matches("example.com", "www.example.com"));
+        assert!(domain_matches("example.com", "a.b.example.com"));
+        assert!(!domain_matches("example.com", "badexample.com"));
+        assert!(!domain_matches("example.com", "example.com.evil.net"));
+        assert!(!domain_matches("example.com", "example.co"));
+    }
+
+    #[test]
+    fn wildcards_match_only_subdomains() {
+        assert!(domain_matches("*.example.com", "www.example.com"));
+        assert!(domain_matches("*.example.com", "a.b.example.com"));
+        assert!(!domain_matches("*.example.com", "example.com"));
+        assert!(!domain_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn wildcards_match_within_labels() {
+        assert!(domain_matches("cdn*.example.com", "cdn1.example.com"));
+        assert!(domain_matches("cdn*.example.com", "cdn.example.com"));
+        assert!(!domain_matches("cdn*.example.com", "img.example.com"));
+        assert!(domain_matches("*.example.*", "www.example.org"));
+        assert!(!domain_matches("*.example.*", "example.org"));
+        assert!(!domain_matches("ab*bc", "abc"));
+    }
+}
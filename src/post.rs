@@ -1,157 +1,563 @@
-use axum::extract::Form;
-use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse};
+use axum::extract::Path;
+use axum::response::{Html, Redirect};
 use axum::Extension;
 
-use info_utils::prelude::*;
+use serde::de::IgnoredAny;
+use tracing::{info, warn, Instrument};
+use url::Url;
 
+use crate::aliases;
+use crate::csrf::CsrfForm;
+use crate::error::{Error, Result};
+use crate::ids::{self, Allocation};
+use crate::logging;
+use crate::proxy::ClientInfo;
+use crate::targeting::{self, Field};
+use crate::telemetry;
+use crate::templates::{self, render};
+use crate::variants;
+use crate::AliasForm;
 use crate::CreateForm;
+use crate::DisableForm;
+use crate::EditForm;
+use crate::RuleForm;
+use crate::ScheduleForm;
 use crate::ServerState;
-use crate::UrlRow;
+use crate::VariantForm;
 
-#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
-struct NextId {
-    id: String,
-    index: Option<i64>,
-    exists: bool,
-}
+pub async fn create_link(
+    mut client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    CsrfForm(form): CsrfForm<CreateForm>,
+) -> Result<Html<String>> {
+    info!("Request to create '{}' -> {}", form.id, form.url.as_str());
+
+    if let Some(requested) = form.domain.as_deref().filter(|d| !d.is_empty()) {
+        match state.domains.find(requested) {
+            Some(domain) => client.host = domain.name.clone(),
+            None => return Err(Error::Validation(format!("Unknown domain '{requested}'."))),
+        }
+    }
 
-#[derive(Debug, Clone, sqlx::FromRow, PartialEq, Eq)]
-struct NextIndex {
-    new_index: Option<i64>,
+    check_url(&form.url, &state)?;
+    let strategy = match form.strategy.as_deref().filter(|s| !s.is_empty()) {
+        Some(strategy) => strategy.parse().map_err(Error::Validation)?,
+        None => state.ids.default_strategy,
+    };
+
+    let link = ids::allocate(&state, &client.host, &form.url, &form.id, strategy).await?;
+    logging::record_link_id(&link.id);
+    if link.allocation == Allocation::Existing {
+        info!("Serving cached id {} -> {}", link.id, link.url);
+    } else {
+        info!("Created new id {} -> {}", link.id, form.url.as_str());
+    }
+    state
+        .metrics
+        .links_created
+        .with_label_values(&[link.allocation.label()])
+        .inc();
+
+    render(&templates::Link {
+        host: &client.host,
+        id: &link.id,
+        short_url: format!("{}/{}", client.base_url(), link.id),
+        url: &link.url,
+    })
 }
 
-pub async fn create_link(
+/// Points an existing link, with all of its aliases, at a new destination and sets its
+/// social card overrides.
+pub async fn update_link(
+    client: ClientInfo,
     Extension(state): Extension<ServerState>,
-    Form(form): Form<CreateForm>,
-) -> impl IntoResponse {
-    log!("Request to create '{}' -> {}", form.id, form.url.as_str());
-
-    let try_id = generate_id(form.clone(), state.clone()).await;
-    if let Ok(id) = try_id {
-        if id.exists {
-            log!("Serving cached id {} -> {}", id.id, form.url.as_str());
-            return Html(format!(
-                r#"<pre>http{}://{}/{} -> <a href="{}"">{}</a></pre>"#,
-                if state.uses_https { "s" } else { "" },
-                state.host,
-                id.id,
-                form.url.as_str(),
-                form.url.as_str(),
-            ))
-            .into_response();
-        }
-        let res;
-        if let Some(index) = id.index {
-            res = sqlx::query(
-                "
-INSERT INTO chela.urls (index,id,url,custom_id)
-VALUES ($1,$2,$3,false)
-              ",
-            )
-            .bind(index)
-            .bind(id.id.clone())
-            .bind(form.url.as_str())
-            .execute(&state.db_pool)
-            .await;
-        } else {
-            res = sqlx::query(
-                "
-INSERT INTO chela.urls (id,url,custom_id)
-VALUES ($1,$2,true)
-              ",
-            )
-            .bind(id.id.clone())
-            .bind(form.url.as_str())
-            .execute(&state.db_pool)
-            .await;
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<EditForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    info!("Request to change '{}' -> {}", id, form.url.as_str());
+    check_url(&form.url, &state)?;
+    let weight = non_empty(&form.weight).map(parse_weight).transpose()?;
+    let og_image = non_empty(&form.og_image);
+    if let Some(image) = og_image {
+        match Url::parse(image) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => {
+                return Err(Error::Validation(format!(
+                    "Invalid card image '{image}': must be an http or https URL"
+                )))
+            }
         }
+    }
 
-        match res {
-            Ok(_) => {
-                log!("Created new id {} -> {}", id.id, form.url.as_str());
-                return (
-                    StatusCode::OK,
-                    Html(format!(
-                        r#"<pre>http{}://{}/{} -> <a href="{}"">{}</a></pre>"#,
-                        if state.uses_https { "s" } else { "" },
-                        state.host,
-                        id.id,
-                        form.url.as_str(),
-                        form.url.as_str(),
-                    )),
-                )
-                    .into_response();
-            }
-            Err(err) => {
-                warn!("{}", err);
-                return (StatusCode::INTERNAL_SERVER_ERROR, Html("Internal error."))
-                    .into_response();
-            }
+    // The fetched title belongs to the old destination, so it is refetched on the next visit.
+    let updated: Option<(String,)> = sqlx::query_as(&format!(
+        "
+UPDATE chela.urls
+SET url = $3,
+    canonical_url = $7,
+    title = CASE WHEN url = $3 THEN title END,
+    title_fetched_at = CASE WHEN url = $3 THEN title_fetched_at END,
+    og_title = $4,
+    og_description = $5,
+    og_image = $6,
+    weight = COALESCE($8, weight)
+WHERE index = (SELECT link FROM chela.aliases WHERE domain = $1 AND {})
+RETURNING id
+        ",
+        state.id_rules.sql_match("id", "$2")
+    ))
+    .bind(&client.host)
+    .bind(&id)
+    .bind(form.url.as_str())
+    .bind(non_empty(&form.og_title))
+    .bind(non_empty(&form.og_description))
+    .bind(og_image)
+    .bind(state.canonicalizer.canonicalize(&form.url))
+    .bind(weight)
+    .fetch_optional(&state.db_pool)
+    .instrument(telemetry::db_span("UPDATE", "chela.urls"))
+    .await?;
+    let Some((id,)) = updated else {
+        return Err(Error::NotFound(format!("No link with id '{id}'.")));
+    };
+
+    info!("Changed '{}' -> {}", id, form.url.as_str());
+    Ok(Redirect::to(&templates::stats_path(&id)))
+}
+
+/// Weights of split destinations, which are whole numbers from 1 to `variants::MAX_WEIGHT`.
+fn parse_weight(weight: &str) -> Result<i32> {
+    match weight.parse() {
+        Ok(weight) if (1..=variants::MAX_WEIGHT).contains(&weight) => Ok(weight),
+        _ => Err(Error::Validation(format!(
+            "Invalid weight '{weight}': must be a whole number from 1 to {}",
+            variants::MAX_WEIGHT
+        ))),
+    }
+}
+
+/// Treats blank form fields as unset.
+fn non_empty(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+/// Disables a link so that it responds with `410 Gone`, or enables it again.
+pub async fn set_disabled(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<DisableForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let updated: Option<(String,)> = sqlx::query_as(&format!(
+        "UPDATE chela.urls SET disabled = $3 WHERE index = (SELECT link FROM chela.aliases WHERE domain = $1 AND {}) RETURNING id",
+        state.id_rules.sql_match("id", "$2")
+    ))
+    .bind(&client.host)
+    .bind(&id)
+    .bind(form.disabled)
+    .fetch_optional(&state.db_pool)
+    .instrument(telemetry::db_span("UPDATE", "chela.urls"))
+    .await?;
+    let Some((id,)) = updated else {
+        return Err(Error::NotFound(format!("No link with id '{id}'.")));
+    };
+
+    info!(
+        "{} '{}'",
+        if form.disabled { "Disabled" } else { "Enabled" },
+        id
+    );
+    Ok(Redirect::to(&templates::stats_path(&id)))
+}
+
+/// Sets when a link becomes active and when it stops being active. Blank times leave that
+/// end of the window open.
+pub async fn set_schedule(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<ScheduleForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let active_from = state
+        .schedule
+        .parse_local(&form.active_from)
+        .map_err(|err| Error::Validation(format!("Invalid start: {err}")))?;
+    let active_until = state
+        .schedule
+        .parse_local(&form.active_until)
+        .map_err(|err| Error::Validation(format!("Invalid end: {err}")))?;
+    if let (Some(from), Some(until)) = (active_from, active_until) {
+        if until <= from {
+            return Err(Error::Validation(
+                "A link's schedule has to end after it starts.".to_string(),
+            ));
         }
-    } else if let Err(err) = try_id {
-        warn!("{}", err);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Html(format!("Internal error: {err}")),
-        )
-            .into_response();
     }
 
-    (StatusCode::INTERNAL_SERVER_ERROR, Html("Internal error.")).into_response()
+    let updated: Option<(String,)> = sqlx::query_as(&format!(
+        "UPDATE chela.urls SET active_from = $3, active_until = $4 WHERE index = (SELECT link FROM chela.aliases WHERE domain = $1 AND {}) RETURNING id",
+        state.id_rules.sql_match("id", "$2")
+    ))
+    .bind(&client.host)
+    .bind(&id)
+    .bind(active_from)
+    .bind(active_until)
+    .fetch_optional(&state.db_pool)
+    .instrument(telemetry::db_span("UPDATE", "chela.urls"))
+    .await?;
+    let Some((id,)) = updated else {
+        return Err(Error::NotFound(format!("No link with id '{id}'.")));
+    };
+
+    info!(
+        "Scheduled '{}' from {:?} until {:?}",
+        id, active_from, active_until
+    );
+    Ok(Redirect::to(&templates::stats_path(&id)))
 }
 
-async fn generate_id(form: CreateForm, state: ServerState) -> eyre::Result<NextId> {
-    if form.id.is_empty() {
-        let existing_row: Result<UrlRow, sqlx::Error> =
-            sqlx::query_as("SELECT * FROM chela.urls WHERE url = $1 AND custom_id = 'false'")
-                .bind(form.url.as_str())
-                .fetch_one(&state.db_pool)
-                .await;
-        if let Ok(row) = existing_row {
-            return Ok(NextId {
-                id: row.id,
-                index: None,
-                exists: true,
-            });
+/// Deletes a link along with its aliases and their analytics.
+pub async fn delete_link(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(_): CsrfForm<IgnoredAny>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let mut tx = state.db_pool.begin().await?;
+    let link = format!(
+        "(SELECT link FROM chela.aliases WHERE domain = $1 AND {})",
+        state.id_rules.sql_match("id", "$2")
+    );
+    sqlx::query(&format!(
+        "
+DELETE FROM chela.tracking
+USING chela.aliases
+WHERE tracking.domain = aliases.domain AND tracking.id = aliases.id
+AND aliases.link = {link}
+        "
+    ))
+    .bind(&client.host)
+    .bind(&id)
+    .execute(&mut *tx)
+    .instrument(telemetry::db_span("DELETE", "chela.tracking"))
+    .await?;
+    // The link's aliases are deleted along with it.
+    let deleted: Option<(String,)> = sqlx::query_as(&format!(
+        "DELETE FROM chela.urls WHERE index = {link} RETURNING id"
+    ))
+    .bind(&client.host)
+    .bind(&id)
+    .fetch_optional(&mut *tx)
+    .instrument(telemetry::db_span("DELETE", "chela.urls"))
+    .await?;
+    let Some((id,)) = deleted else {
+        return Err(Error::NotFound(format!("No link with id '{id}'.")));
+    };
+    tx.commit().await?;
+
+    info!("Deleted '{}'", id);
+    Ok(Redirect::to("/tracking"))
+}
+
+/// Adds another id that leads to the same link.
+pub async fn add_alias(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<AliasForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let alias = state
+        .id_rules
+        .check(&form.alias)
+        .map_err(|err| Error::Validation(format!("Invalid id: {err}")))?;
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+
+    if !ids::claim_alias(&state.db_pool, &client.host, &alias, link.index).await? {
+        state.metrics.link_conflicts.inc();
+        return Err(Error::Conflict(format!("id '{alias}' is already taken")));
+    }
+    state
+        .metrics
+        .links_created
+        .with_label_values(&["alias"])
+        .inc();
+
+    info!("Added alias '{}' for '{}'", alias, link.id);
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Removes an alias along with its analytics. The id a link was created with can't be
+/// removed, only the link as a whole.
+pub async fn remove_alias(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path((id, alias)): Path<(String, String)>,
+    CsrfForm(_): CsrfForm<IgnoredAny>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+    // The alias is matched like any other id, and removed as it is stored.
+    let alias = match aliases::find_link(&state, &client.host, &alias).await? {
+        Some(found) if found.link.index == link.index => found.alias,
+        _ => {
+            return Err(Error::NotFound(format!(
+                "'{alias}' is not an alias of '{}'.",
+                link.id
+            )))
         }
+    };
+    if alias == link.id {
+        return Err(Error::Validation(format!(
+            "'{alias}' is the id this link was created with, delete the link instead."
+        )));
+    }
 
-        let next_index: NextIndex = sqlx::query_as(
-            "SELECT nextval(pg_get_serial_sequence('chela.urls', 'index')) as new_index",
+    let mut tx = state.db_pool.begin().await?;
+    let res = sqlx::query("DELETE FROM chela.aliases WHERE domain = $1 AND id = $2 AND link = $3")
+        .bind(&client.host)
+        .bind(&alias)
+        .bind(link.index)
+        .execute(&mut *tx)
+        .instrument(telemetry::db_span("DELETE", "chela.aliases"))
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::NotFound(format!(
+            "'{alias}' is not an alias of '{}'.",
+            link.id
+        )));
+    }
+    sqlx::query("DELETE FROM chela.tracking WHERE domain = $1 AND id = $2")
+        .bind(&client.host)
+        .bind(&alias)
+        .execute(&mut *tx)
+        .instrument(telemetry::db_span("DELETE", "chela.tracking"))
+        .await?;
+    tx.commit().await?;
+
+    info!("Removed alias '{}' of '{}'", alias, link.id);
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Adds another destination that visitors of a link are split across.
+pub async fn add_variant(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<VariantForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    check_url(&form.url, &state)?;
+    let weight = parse_weight(non_empty(&form.weight).unwrap_or("1"))?;
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+
+    let name = variants::next_name(&state, &link).await?.ok_or_else(|| {
+        Error::Validation("This link already has as many variants as it can.".to_string())
+    })?;
+    let res = sqlx::query(
+        "INSERT INTO chela.variants (link,name,url,weight) VALUES ($1,$2,$3,$4) ON CONFLICT DO NOTHING",
+    )
+    .bind(link.index)
+    .bind(&name)
+    .bind(form.url.as_str())
+    .bind(weight)
+    .execute(&state.db_pool)
+    .instrument(telemetry::db_span("INSERT", "chela.variants"))
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::Conflict(format!(
+            "Variant {name} was just added by someone else, try again."
+        )));
+    }
+
+    info!(
+        "Added variant {} of '{}' -> {}",
+        name,
+        link.id,
+        form.url.as_str()
+    );
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Removes one of a link's extra destinations. Its analytics are kept so that it can still
+/// be compared with the others.
+pub async fn remove_variant(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path((id, name)): Path<(String, String)>,
+    CsrfForm(_): CsrfForm<IgnoredAny>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+    if name == variants::PRIMARY {
+        return Err(Error::Validation(format!(
+            "Variant {name} is the link's own destination, change that instead."
+        )));
+    }
+
+    let res = sqlx::query("DELETE FROM chela.variants WHERE link = $1 AND name = $2")
+        .bind(link.index)
+        .bind(&name)
+        .execute(&state.db_pool)
+        .instrument(telemetry::db_span("DELETE", "chela.variants"))
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::NotFound(format!(
+            "'{}' has no variant {name}.",
+            link.id
+        )));
+    }
+
+    info!("Removed variant {} of '{}'", name, link.id);
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Adds a targeting rule after the link's existing ones.
+pub async fn add_rule(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    CsrfForm(form): CsrfForm<RuleForm>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let field = form.field.parse::<Field>().map_err(Error::Validation)?;
+    if field == Field::Country && !state.geoip.is_enabled() {
+        return Err(Error::Validation(
+            "Country rules need a GeoIP database, see CHELA_GEOIP_DATABASE.".to_string(),
+        ));
+    }
+    let matches = field
+        .parse_values(&form.matches)
+        .map_err(Error::Validation)?;
+    check_url(&form.url, &state)?;
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+
+    sqlx::query(
+        "
+INSERT INTO chela.rules (link,position,field,matches,url)
+SELECT $1, COALESCE(max(position) + 1, 0), $2, $3, $4 FROM chela.rules WHERE link = $1
+        ",
+    )
+    .bind(link.index)
+    .bind(field.name())
+    .bind(&matches)
+    .bind(form.url.as_str())
+    .execute(&state.db_pool)
+    .instrument(telemetry::db_span("INSERT", "chela.rules"))
+    .await?;
+
+    info!(
+        "Added rule for '{}': {} in {:?} -> {}",
+        link.id,
+        field,
+        matches,
+        form.url.as_str()
+    );
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Tries a targeting rule before the one in front of it.
+pub async fn move_rule_up(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path((id, rule)): Path<(String, i64)>,
+    CsrfForm(_): CsrfForm<IgnoredAny>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+
+    let rules = targeting::list(&state, &link).await?;
+    let Some(at) = rules.iter().position(|it| it.id == rule) else {
+        return Err(Error::NotFound(format!(
+            "'{}' has no rule {rule}.",
+            link.id
+        )));
+    };
+    if at > 0 {
+        // Renumbering every rule also untangles positions that happen to be equal.
+        let mut order: Vec<i64> = rules.iter().map(|it| it.id).collect();
+        order.swap(at - 1, at);
+        sqlx::query(
+            "
+UPDATE chela.rules SET position = ordered.position::INTEGER - 1
+FROM unnest($2::BIGINT[]) WITH ORDINALITY AS ordered(id, position)
+WHERE rules.id = ordered.id AND rules.link = $1
+            ",
         )
-        .fetch_one(&state.db_pool)
+        .bind(link.index)
+        .bind(&order)
+        .execute(&state.db_pool)
+        .instrument(telemetry::db_span("UPDATE", "chela.rules"))
         .await?;
+    }
 
-        if let Some(index) = next_index.new_index {
-            let new_id = state.sqids.encode(&[index.try_into()?])?;
-            return Ok(NextId {
-                id: new_id,
-                index: Some(index),
-                exists: false,
-            });
-        }
-    } else {
-        let existing_row: Result<UrlRow, sqlx::Error> =
-            sqlx::query_as("SELECT * FROM chela.urls WHERE id = $1")
-                .bind(form.id.clone())
-                .fetch_one(&state.db_pool)
-                .await;
-        if let Ok(row) = existing_row {
-            if row.url == form.url.as_str() {
-                return Ok(NextId {
-                    id: row.id,
-                    index: None,
-                    exists: true,
-                });
-            }
-            return Err(eyre::eyre!("id '{}' is already taken", row.id));
-        }
-        return Ok(NextId {
-            id: form.id,
-            index: None,
-            exists: false,
-        });
+    info!("Moved rule {} of '{}' up", rule, link.id);
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Removes one of a link's targeting rules.
+pub async fn remove_rule(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path((id, rule)): Path<(String, i64)>,
+    CsrfForm(_): CsrfForm<IgnoredAny>,
+) -> Result<Redirect> {
+    logging::record_link_id(&id);
+    let link = aliases::find_link(&state, &client.host, &id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No link with id '{id}'.")))?
+        .link;
+
+    let res = sqlx::query("DELETE FROM chela.rules WHERE link = $1 AND id = $2")
+        .bind(link.index)
+        .bind(rule)
+        .execute(&state.db_pool)
+        .instrument(telemetry::db_span("DELETE", "chela.rules"))
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::NotFound(format!(
+            "'{}' has no rule {rule}.",
+            link.id
+        )));
     }
 
-    Err(eyre::eyre!("Internal error"))
+    info!("Removed rule {} of '{}'", rule, link.id);
+    Ok(Redirect::to(&templates::stats_path(&link.id)))
+}
+
+/// Applies the URL policy and blocklists to a new destination.
+fn check_url(url: &Url, state: &ServerState) -> Result<()> {
+    if let Err(err) = state.url_policy.check(url, &state.domains) {
+        warn!("Rejected '{}': {}", url.as_str(), err);
+        return Err(Error::Validation(format!("Invalid URL: {err}")));
+    }
+    if let Some(reason) = state.screener.check(url) {
+        warn!("Rejected '{}': {}", url.as_str(), reason);
+        return Err(Error::Validation(
+            "Invalid URL: destination is flagged as malicious".to_string(),
+        ));
+    }
+    Ok(())
 }
@@ -0,0 +1,522 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json, Response};
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use publicsuffix::Psl;
+use reqwest::redirect;
+use serde::Serialize;
+use tracing::{info, warn, Instrument};
+use url::{Host, Url};
+
+use crate::aliases;
+use crate::error::{self, Result};
+use crate::proxy::ClientInfo;
+use crate::telemetry;
+use crate::templates::{self, render};
+use crate::ServerState;
+use crate::UrlRow;
+
+const BUNDLED_SUFFIXES: &str = include_str!("../data/public_suffix_list.dat");
+const MAX_BODY: usize = 256 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const MAX_TITLE: usize = 200;
+/// How long fetching a title may take in total, across DNS lookups and redirects.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const TITLE_MAX_AGE_HOURS: i64 = 24;
+
+/// Everything needed to build the `+` info page: the public suffix list for finding the
+/// registrable domain, and whether destination titles may be fetched.
+#[derive(Clone)]
+pub struct Previewer {
+    suffixes: Arc<publicsuffix::List>,
+    fetch_titles: bool,
+}
+
+impl Previewer {
+    /// Reads `CHELA_PUBLIC_SUFFIX_LIST` (a path to replace the bundled list) and
+    /// `CHELA_FETCH_TITLES` (`false` disables fetching destination titles).
+    pub fn from_env() -> eyre::Result<Self> {
+        let suffixes = match env::var("CHELA_PUBLIC_SUFFIX_LIST") {
+            Ok(path) => std::fs::read_to_string(&path)
+                .map_err(|err| eyre!("failed to read public suffix list {}: {}", path, err))?,
+            Err(_) => BUNDLED_SUFFIXES.to_string(),
+        };
+        let suffixes: publicsuffix::List = suffixes
+            .parse()
+            .map_err(|err| eyre!("invalid public suffix list: {}", err))?;
+
+        let fetch_titles = env::var("CHELA_FETCH_TITLES")
+            .map(|v| !matches!(v.to_lowercase().as_str(), "false" | "0" | "no" | "off"))
+            .unwrap_or(true);
+
+        Ok(Self {
+            suffixes: Arc::new(suffixes),
+            fetch_titles,
+        })
+    }
+
+    /// The registrable part of `host`, e.g. `example.co.uk` for `www.example.co.uk`.
+    pub fn registrable_domain(&self, host: &str) -> Option<String> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let domain = self.suffixes.domain(host.as_bytes())?;
+        String::from_utf8(domain.as_bytes().to_vec()).ok()
+    }
+
+    /// Fetches the `<title>` of an HTML page. Only public addresses are contacted, and
+    /// failures just mean there is no title to show.
+    pub async fn fetch_title(&self, url: &Url) -> Option<String> {
+        if !self.fetch_titles {
+            return None;
+        }
+        match tokio::time::timeout(FETCH_TIMEOUT, fetch_title(url)).await {
+            Ok(title) => title,
+            Err(_) => {
+                warn!("Timed out fetching title of {}", url);
+                None
+            }
+        }
+    }
+}
+
+/// Takes the title out of a page at `url`.
+async fn fetch_title(url: &Url) -> Option<String> {
+    let mut response = match get_public(url).await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            info!("Not fetching title of {}: {}", url, response.status());
+            return None;
+        }
+        Err(err) => {
+            warn!("Failed to fetch title of {}: {}", url, err);
+            return None;
+        }
+    };
+    let is_html = response
+        .headers()
+        .get("content-type")
+        .and_then(|it| it.to_str().ok())
+        .is_some_and(|it| it.contains("html"));
+    if !is_html {
+        return None;
+    }
+
+    let mut body = vec![];
+    while let Ok(Some(chunk)) = response.chunk().await {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_BODY || find_title(&body).is_some() {
+            break;
+        }
+    }
+    find_title(&body)
+}
+
+/// GETs `url`, following up to five redirects. Every hop is resolved up front and only
+/// connects to public addresses, so that previews can't be used to probe the internal
+/// network, including through DNS names that point at it.
+async fn get_public(url: &Url) -> eyre::Result<reqwest::Response> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(concat!("chela/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(3))
+            .redirect(redirect::Policy::none())
+            // A proxy would connect on our behalf, to whatever the name resolves to there.
+            .no_proxy();
+        match url.host() {
+            Some(Host::Domain(domain)) => {
+                let port = url.port_or_known_default().unwrap_or(0);
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                    .await?
+                    .filter(|addr| is_public_ip(addr.ip()))
+                    .collect();
+                if addrs.is_empty() {
+                    return Err(eyre!("'{}' has no public addresses", domain));
+                }
+                builder = builder.resolve_to_addrs(domain, &addrs);
+            }
+            Some(Host::Ipv4(ip)) if is_public_ip(IpAddr::V4(ip)) => {}
+            Some(Host::Ipv6(ip)) if is_public_ip(IpAddr::V6(ip)) => {}
+            _ => return Err(eyre!("'{}' is not a public address", url)),
+        }
+
+        let response = builder
+            .build()?
+            .get(url.as_str())
+            .header("Accept", "text/html")
+            .send()
+            .await?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|it| it.to_str().ok())
+            .ok_or_else(|| eyre!("redirect without a location"))?;
+        url = url.join(location)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(eyre!("redirect to unsupported URL '{}'", url));
+        }
+    }
+    Err(eyre!("too many redirects"))
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                // Shared address space (RFC 6598) and the 240.0.0.0/4 reserved block.
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            let embedded = |high: u16, low: u16| {
+                IpAddr::V4(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)))
+            };
+            match v6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, high, low] => is_public_ip(embedded(high, low)),
+                // NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses lead to the IPv4
+                // address they embed.
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] => is_public_ip(embedded(high, low)),
+                [0x2002, high, low, ..] => is_public_ip(embedded(high, low)),
+                // Unspecified, loopback and the deprecated IPv4-compatible addresses (::/96),
+                // local-use NAT64 (64:ff9b:1::/48) and Teredo (2001::/32), whose IPv4
+                // address is obfuscated.
+                [0, 0, 0, 0, 0, 0, _, _] | [0x64, 0xff9b, 1, ..] | [0x2001, 0, ..] => false,
+                [first, ..] => {
+                    !(v6.is_multicast()
+                        // Unique local (fc00::/7), link-local (fe80::/10) and the deprecated
+                        // site-local (fec0::/10) addresses.
+                        || (first & 0xfe00) == 0xfc00
+                        || (first & 0xffc0) == 0xfe80
+                        || (first & 0xffc0) == 0xfec0)
+                }
+            }
+        }
+    }
+}
+
+/// Extracts and cleans up the contents of the first `<title>` element.
+fn find_title(body: &[u8]) -> Option<String> {
+    let html = String::from_utf8_lossy(body);
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+
+    let title = decode_entities(&html[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if title.is_empty() {
+        return None;
+    }
+    Some(title.chars().take(MAX_TITLE).collect())
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        // Looks at bytes, since the 12th one may be in the middle of a character.
+        let Some(semi) = rest.bytes().take(12).position(|b| b == b';') else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The destination URL split around its registrable domain, so that the page can highlight
+/// the part that actually decides who owns the site.
+struct Destination {
+    before: String,
+    registrable: String,
+    after: String,
+    unicode_host: Option<String>,
+    warnings: Vec<String>,
+}
+
+impl Destination {
+    fn new(url: &Url, previewer: &Previewer) -> Self {
+        let serialized = url.as_str();
+        let host = url.host_str().unwrap_or_default();
+        // The host starts after the scheme and any `user:password@`, which is exactly
+        // where look-alike hosts like `https://example.com@evil.com` try to hide.
+        let authority_start = serialized.find("://").map_or(0, |i| i + 3);
+        let authority_end = serialized[authority_start..]
+            .find(['/', '?', '#'])
+            .map_or(serialized.len(), |i| authority_start + i);
+        let host_start = serialized[authority_start..authority_end]
+            .rfind('@')
+            .map_or(authority_start, |i| authority_start + i + 1);
+        let registrable_end = host_start + host.trim_end_matches('.').len();
+
+        let registrable = match url.host() {
+            Some(Host::Domain(domain)) => previewer
+                .registrable_domain(domain)
+                .unwrap_or(domain.to_string()),
+            _ => host.to_string(),
+        };
+        let registrable_start =
+            registrable_end - registrable.len().min(registrable_end - host_start);
+
+        let mut warnings = vec![];
+        if !url.username().is_empty() || url.password().is_some() {
+            warnings.push(format!(
+                "The address contains a username before the host. The link really goes to {registrable}."
+            ));
+        }
+        let unicode_host = host
+            .split('.')
+            .any(|label| label.starts_with("xn--"))
+            .then(|| idna::domain_to_unicode(host).0);
+        if let Some(unicode) = &unicode_host {
+            warnings.push(format!(
+                "The domain contains international characters and is displayed as '{unicode}'. Look-alike characters are sometimes used to imitate other sites."
+            ));
+        }
+
+        Self {
+            before: serialized[..registrable_start].to_string(),
+            registrable: serialized[registrable_start..registrable_end].to_string(),
+            after: serialized[registrable_end..].to_string(),
+            unicode_host,
+            warnings,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PreviewJson<'a> {
+    id: &'a str,
+    short_url: &'a str,
+    url: &'a str,
+    registrable_domain: &'a str,
+    unicode_host: Option<&'a str>,
+    warnings: &'a [String],
+    title: Option<&'a str>,
+    created_at: Option<DateTime<Utc>>,
+    clicks: i64,
+    qr_svg: String,
+    qr_png: String,
+}
+
+/// Fetches the title of a link's destination in the background, so that the page doesn't
+/// wait for a slow site. Until it arrives, the previous title, if any, is shown. The link is
+/// claimed first, so that visits in the meantime don't start fetches of their own.
+async fn refresh_title(state: &ServerState, link: &UrlRow, url: &Url) -> Result<()> {
+    let claimed = sqlx::query(&format!(
+        "
+UPDATE chela.urls SET title_fetched_at = now()
+WHERE index = $1
+AND (title_fetched_at IS NULL OR title_fetched_at < now() - interval '{TITLE_MAX_AGE_HOURS} hours')
+        "
+    ))
+    .bind(link.index)
+    .execute(&state.db_pool)
+    .instrument(telemetry::db_span("UPDATE", "chela.urls"))
+    .await?
+    .rows_affected()
+        > 0;
+    if !claimed {
+        return Ok(());
+    }
+
+    let state = state.clone();
+    let index = link.index;
+    let stored_url = link.url.clone();
+    let url = url.clone();
+    tokio::spawn(async move {
+        let title = state.previewer.fetch_title(&url).await;
+        // The link may have been pointed elsewhere while the title was being fetched.
+        let updated = sqlx::query(
+            "UPDATE chela.urls SET title = $2, title_fetched_at = now() WHERE index = $1 AND url = $3",
+        )
+        .bind(index)
+        .bind(&title)
+        .bind(&stored_url)
+        .execute(&state.db_pool)
+        .instrument(telemetry::db_span("UPDATE", "chela.urls"))
+        .await;
+        if let Err(err) = updated {
+            warn!("Failed to save title of {}: {}", url, err);
+        }
+    });
+    Ok(())
+}
+
+/// Renders the info page for a link, or JSON if the client asks for it.
+pub async fn respond(
+    headers: &HeaderMap,
+    client: &ClientInfo,
+    state: &ServerState,
+    link: UrlRow,
+    url: &Url,
+) -> Result<Response> {
+    let clicks = aliases::clicks(state, &link).await?;
+
+    let is_fresh = link
+        .title_fetched_at
+        .is_some_and(|fetched| Utc::now() - fetched < chrono::Duration::hours(TITLE_MAX_AGE_HOURS));
+    if !is_fresh && state.previewer.fetch_titles {
+        refresh_title(state, &link, url).await?;
+    }
+    let title = link.title.clone();
+
+    let destination = Destination::new(url, &state.previewer);
+    let short_url = format!("{}/{}", client.base_url(), link.id);
+    let qr_path = format!(
+        "{}/qr/{}",
+        client.base_url(),
+        percent_encoding::utf8_percent_encode(&link.id, percent_encoding::NON_ALPHANUMERIC)
+    );
+
+    if error::prefers_json(headers) {
+        return Ok(Json(PreviewJson {
+            id: &link.id,
+            short_url: &short_url,
+            url: &link.url,
+            registrable_domain: &destination.registrable,
+            unicode_host: destination.unicode_host.as_deref(),
+            warnings: &destination.warnings,
+            title: title.as_deref(),
+            created_at: link.created_at,
+            clicks,
+            qr_svg: format!("{qr_path}.svg"),
+            qr_png: format!("{qr_path}.png"),
+        })
+        .into_response());
+    }
+
+    Ok(render(&templates::Preview {
+        host: &client.host,
+        id: &link.id,
+        short_url: &short_url,
+        url_before: &destination.before,
+        url_registrable: &destination.registrable,
+        url_after: &destination.after,
+        warnings: &destination.warnings,
+        title: title.as_deref(),
+        created_at: link
+            .created_at
+            .map(|created| created.format("%Y-%m-%d %H:%M UTC").to_string()),
+        clicks,
+    })?
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{decode_entities, is_public_ip};
+
+    fn public(ip: &str) -> bool {
+        is_public_ip(ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn rejects_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "100.64.0.1",
+            "169.254.169.254",
+            "::",
+            "::1",
+            "::ffff:10.0.0.1",
+            "::10.0.0.1",
+            "::8.8.8.8",
+            "64:ff9b::a00:1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b:1::1",
+            "2002:a00:1::",
+            "2002:7f00:1::1",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+            "fc00::1",
+            "fe80::1",
+            "fec0::1",
+            "ff02::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in [
+            "8.8.8.8",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+            "2606:4700::1111",
+        ] {
+            assert!(public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn decodes_named_and_numeric_entities() {
+        assert_eq!(
+            decode_entities("Tom &amp; Jerry &lt;3 &#39;hi&#x27; &nbsp;&quot;"),
+            "Tom & Jerry <3 'hi'  \""
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_unterminated_entities() {
+        assert_eq!(
+            decode_entities("a &bogus; b & c &amp"),
+            "a &bogus; b & c &amp"
+        );
+    }
+
+    #[test]
+    fn handles_non_ascii_after_ampersands() {
+        assert_eq!(decode_entities("& Jérôme — café"), "& Jérôme — café");
+        assert_eq!(decode_entities("&ééééééé;"), "&ééééééé;");
+        assert_eq!(decode_entities("é&amp;ü"), "é&ü");
+    }
+}
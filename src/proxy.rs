@@ -0,0 +1,401 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, StatusCode, Uri};
+use ipnet::IpNet;
+
+use crate::ServerState;
+use crate::UdsConnectInfo;
+
+const DEFAULT_TRUSTED: &str =
+    "127.0.0.0/8,::1/128,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7";
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    /// Trust whichever peer connects directly, as chela did before proxies could be listed.
+    any_peer: bool,
+}
+
+/// One hop of a forwarding chain, as described by a `Forwarded` element or the matching
+/// entries of the `X-Forwarded-*` headers.
+#[derive(Debug, Clone, Default)]
+struct Hop {
+    client: String,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl TrustedProxies {
+    /// Reads `CHELA_TRUSTED_PROXIES`. If only `CHELA_BEHIND_PROXY` is set, any directly
+    /// connected peer is trusted, and so are loopback and private ranges further down the
+    /// forwarding chain.
+    pub fn from_env(behind_proxy: bool) -> eyre::Result<Self> {
+        let (list, any_peer) = match env::var("CHELA_TRUSTED_PROXIES") {
+            Ok(list) => (list, false),
+            Err(_) if behind_proxy => (DEFAULT_TRUSTED.to_string(), true),
+            Err(_) => (String::new(), false),
+        };
+
+        let mut nets = vec![];
+        for entry in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let net = match entry.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => IpNet::from(entry.parse::<IpAddr>()?),
+            };
+            nets.push(net);
+        }
+        Ok(Self { nets, any_peer })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.any_peer || !self.nets.is_empty()
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Walks the forwarding chain right to left, starting at the directly connected peer,
+    /// and stops at the first address that is not a trusted proxy. A `peer` of `None` is a
+    /// Unix socket peer, which is always trusted.
+    fn resolve(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<Hop> {
+        if !self.is_enabled() || peer.is_some_and(|ip| !self.any_peer && !self.is_trusted(ip)) {
+            return None;
+        }
+
+        let hops = forwarding_chain(headers);
+        for hop in hops.iter().rev() {
+            match parse_node(&hop.client) {
+                Some(ip) if self.is_trusted(ip) => continue,
+                _ => return Some(hop.clone()),
+            }
+        }
+        hops.into_iter().next()
+    }
+}
+
+/// Where a request really came from, after accounting for trusted proxies. `host` is always
+/// one of the configured domains.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: String,
+    pub scheme: String,
+    pub host: String,
+}
+
+impl ClientInfo {
+    pub fn resolve(
+        headers: &HeaderMap,
+        uri: &Uri,
+        extensions: &Extensions,
+        state: &ServerState,
+    ) -> Self {
+        let (peer, peer_string) = if let Some(ConnectInfo(addr)) =
+            extensions.get::<ConnectInfo<SocketAddr>>()
+        {
+            (Some(addr.ip()), Some(addr.ip().to_string()))
+        } else if let Some(ConnectInfo(addr)) = extensions.get::<ConnectInfo<UdsConnectInfo>>() {
+            (None, Some(format!("{:?}", addr.peer_addr)))
+        } else {
+            (None, None)
+        };
+
+        let hop = if peer_string.is_some() {
+            state.proxies.resolve(headers, peer)
+        } else {
+            None
+        };
+        let default_scheme = if state.uses_https { "https" } else { "http" };
+        // Only hosts that are configured as one of our domains are used, so a spoofed
+        // `Host` header cannot change the URLs that chela hands out.
+        let request_host = headers
+            .get("host")
+            .and_then(|it| it.to_str().ok())
+            .or(uri.authority().map(|a| a.as_str()));
+
+        match hop {
+            Some(hop) => Self {
+                ip: normalize_node(&hop.client),
+                scheme: hop
+                    .proto
+                    .filter(|p| p == "http" || p == "https")
+                    .unwrap_or(default_scheme.to_string()),
+                host: state
+                    .domains
+                    .resolve(hop.host.as_deref().or(request_host))
+                    .name
+                    .clone(),
+            },
+            None => Self {
+                ip: if state.proxies.is_enabled() && peer.is_none() {
+                    String::new()
+                } else {
+                    peer_string.unwrap_or_default()
+                },
+                scheme: default_scheme.to_string(),
+                host: state.domains.resolve(request_host).name.clone(),
+            },
+        }
+    }
+
+    /// The URL that this chela instance is reachable at for this client, e.g. `https://a.com`.
+    pub fn base_url(&self) -> String {
+        format!("{}://{}", self.scheme, self.host)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let state = parts
+            .extensions
+            .get::<ServerState>()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Missing server state"))?;
+        Ok(Self::resolve(
+            &parts.headers,
+            &parts.uri,
+            &parts.extensions,
+            state,
+        ))
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Collects the forwarding chain, preferring RFC 7239 `Forwarded` over `X-Forwarded-For`,
+/// with `X-Real-IP` as a single-hop fallback.
+fn forwarding_chain(headers: &HeaderMap) -> Vec<Hop> {
+    let forwarded = header_values(headers, "forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                let mut hop = Hop::default();
+                for pair in element.split(';') {
+                    let Some((key, value)) = pair.split_once('=') else {
+                        continue;
+                    };
+                    let value = value.trim().trim_matches('"').to_string();
+                    match key.trim().to_lowercase().as_str() {
+                        "for" => hop.client = value,
+                        "proto" => hop.proto = Some(value.to_lowercase()),
+                        "host" => hop.host = Some(value),
+                        _ => {}
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+
+    let forwarded_for = header_values(headers, "x-forwarded-for");
+    if !forwarded_for.is_empty() {
+        let protos = header_values(headers, "x-forwarded-proto");
+        let hosts = header_values(headers, "x-forwarded-host");
+        // Proxies usually append to `X-Forwarded-For` but overwrite the other headers, so
+        // only line them up with hops when the lengths agree.
+        let pick = |values: &[&str], i: usize| -> Option<String> {
+            if values.len() == forwarded_for.len() {
+                Some(values[i].to_string())
+            } else {
+                values.last().map(|v| v.to_string())
+            }
+        };
+        return forwarded_for
+            .iter()
+            .enumerate()
+            .map(|(i, client)| Hop {
+                client: client.to_string(),
+                proto: pick(&protos, i).map(|p| p.to_lowercase()),
+                host: pick(&hosts, i),
+            })
+            .collect();
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|it| it.to_str().ok())
+        .map(|ip| {
+            vec![Hop {
+                client: ip.trim().to_string(),
+                proto: header_values(headers, "x-forwarded-proto")
+                    .last()
+                    .map(|p| p.to_lowercase()),
+                host: header_values(headers, "x-forwarded-host")
+                    .last()
+                    .map(|h| h.to_string()),
+            }]
+        })
+        .unwrap_or_default()
+}
+
+/// Parses a node from a forwarding header: `1.2.3.4`, `1.2.3.4:80`, `[::1]`, `[::1]:80`
+/// or a bare IPv6 address.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| n.parse().ok())
+}
+
+fn normalize_node(node: &str) -> String {
+    parse_node(node).map_or(node.to_string(), |ip| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::HeaderMap;
+
+    use super::{forwarding_chain, normalize_node, parse_node, TrustedProxies, DEFAULT_TRUSTED};
+
+    fn proxies(list: &str, any_peer: bool) -> TrustedProxies {
+        TrustedProxies {
+            nets: list.split(',').map(|net| net.parse().unwrap()).collect(),
+            any_peer,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    fn client(proxies: &TrustedProxies, headers: &HeaderMap, peer: &str) -> Option<String> {
+        proxies
+            .resolve(headers, ip(peer))
+            .map(|hop| normalize_node(&hop.client))
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let proxies = proxies("10.0.0.0/8", false);
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("forwarded", "for=198.51.100.1;proto=https;host=a.com"),
+            ("x-real-ip", "198.51.100.1"),
+        ]);
+        assert_eq!(client(&proxies, &spoofed, "203.0.113.9"), None);
+        assert_eq!(
+            client(&proxies, &spoofed, "10.0.0.1"),
+            Some("198.51.100.1".to_string())
+        );
+        assert_eq!(
+            client(&TrustedProxies::default(), &spoofed, "10.0.0.1"),
+            None
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let proxies = proxies("10.0.0.0/8,::1/128", false);
+        let chain = headers(&[("x-forwarded-for", "198.51.100.1, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(
+            client(&proxies, &chain, "10.0.0.1"),
+            Some("203.0.113.9".to_string())
+        );
+
+        let chain = headers(&[(
+            "forwarded",
+            "for=198.51.100.1, for=203.0.113.9;proto=https, for=\"[::1]:8080\"",
+        )]);
+        let hop = proxies.resolve(&chain, ip("10.0.0.1")).unwrap();
+        assert_eq!(hop.client, "203.0.113.9");
+        assert_eq!(hop.proto.as_deref(), Some("https"));
+
+        // When every hop is trusted, the leftmost one is the client.
+        let chain = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            client(&proxies, &chain, "10.0.0.1"),
+            Some("10.0.0.3".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_forwarded_ipv6_nodes() {
+        let chain = forwarding_chain(&headers(&[(
+            "forwarded",
+            "For=\"[2001:db8:cafe::17]:4711\";Proto=HTTPS;Host=a.com",
+        )]));
+        assert_eq!(chain.len(), 1);
+        assert_eq!(parse_node(&chain[0].client), ip("2001:db8:cafe::17"));
+        assert_eq!(chain[0].proto.as_deref(), Some("https"));
+        assert_eq!(chain[0].host.as_deref(), Some("a.com"));
+
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("192.0.2.60:443"), ip("192.0.2.60"));
+        assert_eq!(normalize_node("[2001:DB8::1]:80"), "2001:db8::1");
+    }
+
+    #[test]
+    fn treats_obfuscated_and_unknown_nodes_as_untrusted() {
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("[_hidden]"), None);
+
+        let proxies = proxies("10.0.0.0/8", false);
+        let chain = headers(&[("forwarded", "for=198.51.100.1, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(
+            client(&proxies, &chain, "10.0.0.1"),
+            Some("_hidden".to_string())
+        );
+        let chain = headers(&[("forwarded", "for=unknown;proto=https")]);
+        assert_eq!(
+            client(&proxies, &chain, "10.0.0.1"),
+            Some("unknown".to_string())
+        );
+    }
+
+    #[test]
+    fn trusts_any_peer_only_when_asked_to() {
+        let chain = headers(&[("x-forwarded-for", "198.51.100.1, 10.0.0.2")]);
+        let any_peer = proxies(DEFAULT_TRUSTED, true);
+        assert_eq!(
+            client(&any_peer, &chain, "203.0.113.9"),
+            Some("198.51.100.1".to_string())
+        );
+        let listed = proxies(DEFAULT_TRUSTED, false);
+        assert_eq!(client(&listed, &chain, "203.0.113.9"), None);
+        // Unix socket peers are always trusted.
+        assert_eq!(
+            listed.resolve(&chain, None).map(|hop| hop.client),
+            Some("198.51.100.1".to_string())
+        );
+    }
+}
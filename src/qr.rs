@@ -0,0 +1,266 @@
+use std::fmt::Write;
+
+use axum::extract::{Path, Query};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use eyre::eyre;
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+
+use crate::aliases;
+use crate::error::{Error, Result};
+use crate::logging;
+use crate::proxy::ClientInfo;
+use crate::ServerState;
+
+const DEFAULT_SIZE: u32 = 256;
+const MAX_SIZE: u32 = 1024;
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Svg,
+    Png,
+}
+
+/// Query parameters for `/qr/:id`. `size` is the width of the image in pixels, `ec` the
+/// error correction level (`L`, `M`, `Q` or `H`) and `margin` the quiet zone in modules.
+#[derive(Debug, Default, Deserialize)]
+pub struct QrOptions {
+    size: Option<u32>,
+    ec: Option<String>,
+    margin: Option<u32>,
+    format: Option<String>,
+}
+
+/// Renders a QR code for a short link, e.g. `/qr/abc.png?size=512&ec=H`. The format comes
+/// from a `.svg` or `.png` extension, the `format` parameter, or defaults to SVG.
+pub async fn qr(
+    client: ClientInfo,
+    Extension(state): Extension<ServerState>,
+    Path(id): Path<String>,
+    Query(options): Query<QrOptions>,
+) -> Result<Response> {
+    logging::record_link_id(&id);
+
+    let ec_level = match options.ec.as_deref().map(str::to_uppercase).as_deref() {
+        None | Some("M") => EcLevel::M,
+        Some("L") => EcLevel::L,
+        Some("Q") => EcLevel::Q,
+        Some("H") => EcLevel::H,
+        Some(other) => {
+            return Err(Error::Validation(format!(
+                "Unknown error correction level '{other}', use L, M, Q or H."
+            )))
+        }
+    };
+    let size = options.size.unwrap_or(DEFAULT_SIZE).clamp(1, MAX_SIZE);
+    let margin = options.margin.unwrap_or(DEFAULT_MARGIN).min(MAX_MARGIN);
+
+    let mut found = None;
+    for (candidate, format) in candidates(&id, options.format.as_deref())? {
+        if let Some(link) = aliases::find_link(&state, &client.host, &candidate).await? {
+            found = Some((link, format));
+            break;
+        }
+    }
+    let Some((found, format)) = found else {
+        return Err(Error::NotFound(format!("No link with id '{id}'.")));
+    };
+    let id = found.alias;
+
+    let short_url = format!("{}/{}", client.base_url(), id);
+    let code = QrCode::with_error_correction_level(short_url.as_bytes(), ec_level)
+        .map_err(|err| eyre!("failed to encode '{}' as a QR code: {}", short_url, err))?;
+    let modules = Modules::new(&code, margin);
+
+    let cache = (header::CACHE_CONTROL, "public, max-age=86400");
+    Ok(match format {
+        Format::Svg => (
+            [(header::CONTENT_TYPE, "image/svg+xml"), cache],
+            modules.to_svg(size),
+        )
+            .into_response(),
+        Format::Png => (
+            [(header::CONTENT_TYPE, "image/png"), cache],
+            modules.to_png(size)?,
+        )
+            .into_response(),
+    })
+}
+
+/// The links and formats that a request for `id` can mean, in the order they are tried. Ids
+/// can contain `.`, so `/qr/a.png` is the QR code of a link `a.png` if there is one, and a
+/// PNG of the QR code of `a` otherwise.
+fn candidates(id: &str, format: Option<&str>) -> Result<Vec<(String, Format)>> {
+    let format = match format.map(str::to_lowercase).as_deref() {
+        None | Some("svg") => Format::Svg,
+        Some("png") => Format::Png,
+        Some(other) => {
+            return Err(Error::Validation(format!(
+                "Unknown QR code format '{other}', use 'svg' or 'png'."
+            )))
+        }
+    };
+
+    let mut candidates = vec![(id.to_string(), format)];
+    if let Some(stripped) = id.strip_suffix(".svg") {
+        candidates.push((stripped.to_string(), Format::Svg));
+    } else if let Some(stripped) = id.strip_suffix(".png") {
+        candidates.push((stripped.to_string(), Format::Png));
+    }
+    Ok(candidates)
+}
+
+/// The dark modules of a QR code, surrounded by a quiet zone of `margin` modules.
+struct Modules {
+    dark: Vec<bool>,
+    code_width: usize,
+    margin: usize,
+}
+
+impl Modules {
+    fn new(code: &QrCode, margin: u32) -> Self {
+        Self {
+            dark: code
+                .to_colors()
+                .into_iter()
+                .map(|color| color == Color::Dark)
+                .collect(),
+            code_width: code.width(),
+            margin: margin as usize,
+        }
+    }
+
+    /// Width in modules, including the quiet zone.
+    fn width(&self) -> usize {
+        self.code_width + 2 * self.margin
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        let (Some(x), Some(y)) = (x.checked_sub(self.margin), y.checked_sub(self.margin)) else {
+            return false;
+        };
+        x < self.code_width && y < self.code_width && self.dark[y * self.code_width + x]
+    }
+
+    fn to_svg(&self, size: u32) -> String {
+        let width = self.width();
+        let mut path = String::new();
+        for y in 0..width {
+            for x in 0..width {
+                if self.is_dark(x, y) {
+                    let _ = write!(path, "M{x},{y}h1v1h-1z");
+                }
+            }
+        }
+        format!(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" viewBox="0 0 {width} {width}" shape-rendering="crispEdges"><rect width="100%" height="100%" fill="#fff"/><path fill="#000" d="{path}"/></svg>
+"##
+        )
+    }
+
+    /// Scales every module to a whole number of pixels, so the image is never blurry but may
+    /// be slightly smaller than `size`.
+    fn to_png(&self, size: u32) -> Result<Vec<u8>> {
+        let width = self.width();
+        let scale = (size as usize / width).max(1);
+        let pixels = width * scale;
+
+        let mut image = Vec::with_capacity(pixels * pixels);
+        for y in 0..pixels {
+            for x in 0..pixels {
+                image.push(if self.is_dark(x / scale, y / scale) {
+                    0
+                } else {
+                    255
+                });
+            }
+        }
+
+        let mut png = vec![];
+        let dimension = u32::try_from(pixels).map_err(|err| eyre!(err))?;
+        let mut encoder = png::Encoder::new(&mut png, dimension, dimension);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|err| eyre!("failed to write PNG header: {}", err))?;
+        writer
+            .write_image_data(&image)
+            .map_err(|err| eyre!("failed to write PNG data: {}", err))?;
+        writer
+            .finish()
+            .map_err(|err| eyre!("failed to finish PNG: {}", err))?;
+        Ok(png)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qrcode::{EcLevel, QrCode};
+
+    use super::{candidates, Format, Modules};
+
+    fn modules() -> Modules {
+        let code = QrCode::with_error_correction_level(b"https://a.com/abc", EcLevel::M).unwrap();
+        Modules::new(&code, 4)
+    }
+
+    #[test]
+    fn tries_the_whole_id_before_the_extension() {
+        assert_eq!(
+            candidates("abc.png", None).unwrap(),
+            vec![
+                ("abc.png".to_string(), Format::Svg),
+                ("abc".to_string(), Format::Png)
+            ]
+        );
+        assert_eq!(
+            candidates("abc.svg", Some("png")).unwrap(),
+            vec![
+                ("abc.svg".to_string(), Format::Png),
+                ("abc".to_string(), Format::Svg)
+            ]
+        );
+        assert_eq!(
+            candidates("v1.2", Some("PNG")).unwrap(),
+            vec![("v1.2".to_string(), Format::Png)]
+        );
+        assert_eq!(
+            candidates("abc", None).unwrap(),
+            vec![("abc".to_string(), Format::Svg)]
+        );
+        assert!(candidates("abc", Some("gif")).is_err());
+    }
+
+    #[test]
+    fn renders_svg() {
+        let modules = modules();
+        let svg = modules.to_svg(300);
+        let width = modules.width();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains(r#"width="300" height="300""#));
+        assert!(svg.contains(&format!(r#"viewBox="0 0 {width} {width}""#)));
+        // The quiet zone stays light and the finder pattern starts right after it.
+        assert!(!svg.contains("M0,0h1"));
+        assert!(svg.contains("M4,4h1v1h-1z"));
+    }
+
+    #[test]
+    fn renders_png_in_whole_pixels_per_module() {
+        let modules = modules();
+        let width = modules.width() as u32;
+        for (size, expected) in [(256, 256 / width * width), (1, width)] {
+            let png = modules.to_png(size).unwrap();
+            let decoder = png::Decoder::new(png.as_slice());
+            let reader = decoder.read_info().unwrap();
+            let info = reader.info();
+            assert_eq!((info.width, info.height), (expected, expected));
+            assert_eq!(info.color_type, png::ColorType::Grayscale);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Extension;
+
+use tracing::warn;
+
+use crate::proxy::ClientInfo;
+use crate::ServerState;
+use crate::UdsConnectInfo;
+
+/// How many keys are tracked at most. Past that, buckets are dropped to make room even if
+/// they haven't refilled yet, which only gives their clients a fresh burst.
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per key: each key may make `capacity` requests in a burst, refilled at
+/// `capacity` tokens per `period`.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    max_buckets: usize,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        Self {
+            capacity: capacity.into(),
+            refill_per_sec: f64::from(capacity) / period.as_secs_f64(),
+            max_buckets: MAX_BUCKETS,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Parses a limit of the form `<requests>/<seconds>`, e.g. `10/60`.
+    pub fn parse(limit: &str) -> eyre::Result<Self> {
+        let (requests, seconds) = limit
+            .split_once('/')
+            .ok_or_else(|| eyre::eyre!("rate limit '{}' must look like '10/60'", limit))?;
+        let requests: u32 = requests.trim().parse()?;
+        let seconds: u64 = seconds.trim().parse()?;
+        if requests == 0 || seconds == 0 {
+            return Err(eyre::eyre!("rate limit '{}' must be non-zero", limit));
+        }
+        Ok(Self::new(requests, Duration::from_secs(seconds)))
+    }
+
+    /// Takes a token for `key`, or returns how long the caller has to wait for one.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(key) {
+            self.make_room(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+
+    /// Drops buckets that have refilled completely, since they behave like new ones.
+    fn prune(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.drop_refilled(&mut buckets, Instant::now());
+    }
+
+    fn drop_refilled(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.refill_per_sec < self.capacity
+        });
+    }
+
+    /// Drops refilled buckets and, if that leaves more than nine tenths of `max_buckets`,
+    /// the ones that were used longest ago. Freeing a tenth at once keeps many new keys
+    /// from scanning all buckets each.
+    fn make_room(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        self.drop_refilled(buckets, now);
+        let keep = self.max_buckets - self.max_buckets / 10;
+        if buckets.len() > keep {
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let evict = updated.len() - keep;
+            let (_, cutoff, _) = updated.select_nth_unstable(evict - 1);
+            let cutoff = *cutoff;
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub create: Option<RateLimiter>,
+    pub redirect: Option<RateLimiter>,
+    pub api_tokens: HashSet<String>,
+}
+
+impl RateLimits {
+    pub fn from_env() -> eyre::Result<Self> {
+        let limiter = |var: &str| -> eyre::Result<Option<RateLimiter>> {
+            match env::var(var) {
+                Ok(limit) if !limit.is_empty() => Ok(Some(RateLimiter::parse(&limit)?)),
+                _ => Ok(None),
+            }
+        };
+        let limits = Self {
+            create: limiter("CHELA_CREATE_RATE_LIMIT")?,
+            redirect: limiter("CHELA_REDIRECT_RATE_LIMIT")?,
+            api_tokens: env::var("CHELA_API_TOKENS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+        };
+
+        if limits.create.is_some() || limits.redirect.is_some() {
+            let pruned = limits.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    ticker.tick().await;
+                    for limiter in [&pruned.create, &pruned.redirect].into_iter().flatten() {
+                        limiter.prune();
+                    }
+                }
+            });
+        }
+
+        Ok(limits)
+    }
+
+    /// Requests carrying a known API token in `Authorization: Bearer` are limited per token,
+    /// everything else per client IP.
+    fn key(&self, headers: &HeaderMap, ip: String) -> String {
+        let token = headers
+            .get("authorization")
+            .and_then(|it| it.to_str().ok())
+            .and_then(|it| it.strip_prefix("Bearer "))
+            .map(str::trim);
+        match token {
+            Some(token) if self.api_tokens.contains(token) => format!("token:{token}"),
+            _ => format!("ip:{ip}"),
+        }
+    }
+}
+
+pub async fn limit_create(
+    Extension(state): Extension<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    limit(state.rate_limits.create.as_ref(), &state, request, next).await
+}
+
+pub async fn limit_redirect(
+    Extension(state): Extension<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    limit(state.rate_limits.redirect.as_ref(), &state, request, next).await
+}
+
+async fn limit(
+    limiter: Option<&RateLimiter>,
+    state: &ServerState,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = limiter else {
+        return next.run(request).await;
+    };
+
+    let ip = ClientInfo::resolve(
+        request.headers(),
+        request.uri(),
+        request.extensions(),
+        state,
+    )
+    .ip;
+    // Unix socket peers have no address of their own, so unless a trusted proxy forwarded
+    // one they would all share a single bucket, and one caller could use it up for all. They
+    // are only limited per API token.
+    let unix_peer = request
+        .extensions()
+        .get::<ConnectInfo<UdsConnectInfo>>()
+        .is_some();
+    let anonymous = unix_peer && ip.parse::<IpAddr>().is_err();
+    let key = state.rate_limits.key(request.headers(), ip);
+    if anonymous && key.starts_with("ip:") {
+        return next.run(request).await;
+    }
+    match limiter.check(&key) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            warn!("Rate limited {} on {}", key, request.uri().path());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", retry_after.to_string())],
+                Html("<pre>Too many requests.</pre>"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    fn waits(result: Result<(), Duration>) -> f64 {
+        result.unwrap_err().as_secs_f64()
+    }
+
+    #[test]
+    fn allows_a_burst_and_refills_over_time() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(3));
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("ip:a", start), Ok(()));
+        }
+        assert!((waits(limiter.check_at("ip:a", start)) - 1.0).abs() < 1e-6);
+        let later = start + Duration::from_millis(500);
+        assert!((waits(limiter.check_at("ip:a", later)) - 0.5).abs() < 1e-6);
+        // Other keys have buckets of their own.
+        assert_eq!(limiter.check_at("ip:b", start), Ok(()));
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at("ip:a", later), Ok(()));
+        assert!(limiter.check_at("ip:a", later).is_err());
+
+        // Buckets never hold more than a burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("ip:a", later), Ok(()));
+        }
+        assert!(limiter.check_at("ip:a", later).is_err());
+    }
+
+    #[test]
+    fn caps_the_number_of_buckets() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        limiter.max_buckets = 10;
+        let start = Instant::now();
+        for i in 0..10 {
+            let now = start + Duration::from_millis(i);
+            assert_eq!(limiter.check_at(&format!("ip:{i}"), now), Ok(()));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 10);
+
+        // The bucket used longest ago makes room for the new one.
+        let now = start + Duration::from_millis(10);
+        assert_eq!(limiter.check_at("ip:new", now), Ok(()));
+        {
+            let buckets = limiter.buckets.lock().unwrap();
+            assert_eq!(buckets.len(), 10);
+            assert!(!buckets.contains_key("ip:0"));
+            assert!(buckets.contains_key("ip:1"));
+        }
+
+        // Refilled buckets go first, however recently they were used.
+        let now = start + Duration::from_secs(120);
+        assert_eq!(limiter.check_at("ip:9", now), Ok(()));
+        assert_eq!(limiter.check_at("ip:9", now), Ok(()));
+        assert_eq!(limiter.check_at("ip:newer", now), Ok(()));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.contains_key("ip:9"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::env;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use url::Url;
+
+use crate::UrlRow;
+
+/// The formats of `datetime-local` inputs, which leave out the seconds unless they are set.
+const LOCAL_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"];
+
+/// The time zone that link schedules and time-based rules are written in, and where
+/// visitors go while a link isn't active yet.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub time_zone: Tz,
+    pending_url: Option<Url>,
+}
+
+impl Schedule {
+    /// Reads `CHELA_TIME_ZONE` (an IANA name, `UTC` by default) and
+    /// `CHELA_NOT_YET_AVAILABLE_URL`.
+    pub fn from_env() -> eyre::Result<Self> {
+        let time_zone = match env::var("CHELA_TIME_ZONE") {
+            Ok(name) if !name.is_empty() => name.parse().map_err(|_| {
+                eyre::eyre!(
+                    "CHELA_TIME_ZONE must be a time zone like Europe/Berlin, not '{}'",
+                    name
+                )
+            })?,
+            _ => Tz::UTC,
+        };
+        let pending_url = match env::var("CHELA_NOT_YET_AVAILABLE_URL") {
+            Ok(url) if !url.is_empty() => {
+                let parsed = Url::parse(&url).map_err(|err| {
+                    eyre::eyre!("CHELA_NOT_YET_AVAILABLE_URL '{}' is invalid: {}", url, err)
+                })?;
+                if parsed.scheme() != "http" && parsed.scheme() != "https" {
+                    return Err(eyre::eyre!(
+                        "CHELA_NOT_YET_AVAILABLE_URL must be an http or https URL, not '{}'",
+                        url
+                    ));
+                }
+                Some(parsed)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            time_zone,
+            pending_url,
+        })
+    }
+
+    /// Where visitors of links that aren't active yet are sent instead of Chela's own page.
+    pub fn pending_url(&self) -> Option<&Url> {
+        self.pending_url.as_ref()
+    }
+
+    pub fn now(&self) -> DateTime<Tz> {
+        Utc::now().with_timezone(&self.time_zone)
+    }
+
+    /// Reads the value of a `datetime-local` input as a time in our time zone. Blank values
+    /// are unset.
+    pub fn parse_local(&self, value: &str) -> Result<Option<DateTime<Utc>>, String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let local = LOCAL_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+            .ok_or_else(|| format!("'{value}' is not a date and time like 2024-06-01T09:00"))?;
+        // Times that are skipped when the clocks go forward don't exist. Times that happen
+        // twice when they go back mean the first one.
+        let time = self
+            .time_zone
+            .from_local_datetime(&local)
+            .earliest()
+            .ok_or_else(|| format!("{value} doesn't exist in {}", self.time_zone))?;
+        Ok(Some(time.with_timezone(&Utc)))
+    }
+
+    /// `time` as the value of a `datetime-local` input.
+    pub fn format_local(&self, time: Option<DateTime<Utc>>) -> String {
+        time.map(|time| {
+            time.with_timezone(&self.time_zone)
+                .format("%Y-%m-%dT%H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+    }
+
+    /// `time` for people to read.
+    pub fn display(&self, time: DateTime<Utc>) -> String {
+        time.with_timezone(&self.time_zone)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Pending,
+    Active,
+    Expired,
+}
+
+/// Whether `link` leads anywhere at `now`. It becomes active at `active_from` and stops
+/// being active at `active_until`.
+pub fn availability(link: &UrlRow, now: DateTime<Utc>) -> Availability {
+    if link.active_from.is_some_and(|from| now < from) {
+        Availability::Pending
+    } else if link.active_until.is_some_and(|until| now >= until) {
+        Availability::Expired
+    } else {
+        Availability::Active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    use super::{availability, Availability, Schedule};
+    use crate::UrlRow;
+
+    fn schedule(time_zone: &str) -> Schedule {
+        Schedule {
+            time_zone: time_zone.parse().unwrap(),
+            pending_url: None,
+        }
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_local_times() {
+        let berlin = schedule("Europe/Berlin");
+        assert_eq!(
+            berlin.parse_local("2024-06-01T09:00"),
+            Ok(Some(utc("2024-06-01T07:00:00Z")))
+        );
+        assert_eq!(
+            berlin.parse_local(" 2024-01-01T09:00:30 "),
+            Ok(Some(utc("2024-01-01T08:00:30Z")))
+        );
+        assert_eq!(berlin.parse_local("  "), Ok(None));
+        assert!(berlin.parse_local("tomorrow").is_err());
+    }
+
+    #[test]
+    fn rejects_times_skipped_by_dst() {
+        let new_york = schedule("America/New_York");
+        assert_eq!(
+            new_york.parse_local("2024-03-10T02:30"),
+            Err("2024-03-10T02:30 doesn't exist in America/New_York".to_string())
+        );
+    }
+
+    #[test]
+    fn takes_the_first_of_repeated_times() {
+        let new_york = schedule("America/New_York");
+        assert_eq!(
+            new_york.parse_local("2024-11-03T01:30"),
+            Ok(Some(utc("2024-11-03T05:30:00Z")))
+        );
+    }
+
+    #[test]
+    fn formats_local_times() {
+        let new_york = schedule("America/New_York");
+        let time = utc("2024-06-01T13:00:00Z");
+        assert_eq!(new_york.format_local(Some(time)), "2024-06-01T09:00");
+        assert_eq!(new_york.format_local(None), "");
+        assert_eq!(new_york.display(time), "2024-06-01 09:00 EDT");
+    }
+
+    #[test]
+    fn links_are_active_between_from_and_until() {
+        let from = Utc.with_ymd_and_hms(2024, 6, 1, 9, 0, 0).unwrap();
+        let until = from + Duration::days(1);
+        let link = UrlRow {
+            active_from: Some(from),
+            active_until: Some(until),
+            ..UrlRow::default()
+        };
+        let second = Duration::seconds(1);
+        assert_eq!(availability(&link, from - second), Availability::Pending);
+        assert_eq!(availability(&link, from), Availability::Active);
+        assert_eq!(availability(&link, until - second), Availability::Active);
+        assert_eq!(availability(&link, until), Availability::Expired);
+        assert_eq!(availability(&UrlRow::default(), from), Availability::Active);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenAction {
+    /// Flagged links are refused with `410 Gone`.
+    Block,
+    /// Flagged links show an interstitial warning page instead of redirecting.
+    Warn,
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    domains: HashSet<String>,
+    hash_prefixes: HashSet<Vec<u8>>,
+    prefix_lengths: Vec<usize>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Screener {
+    pub action: ScreenAction,
+    domain_files: Vec<PathBuf>,
+    hash_files: Vec<PathBuf>,
+    lists: Arc<RwLock<Lists>>,
+}
+
+impl Screener {
+    pub fn from_env() -> Self {
+        let paths = |var: &str| -> Vec<PathBuf> {
+            env::var(var)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        };
+        let action = match env::var("CHELA_BLOCKLIST_ACTION")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "warn" => ScreenAction::Warn,
+            _ => ScreenAction::Block,
+        };
+
+        let screener = Self {
+            action,
+            domain_files: paths("CHELA_BLOCKLIST_DOMAINS"),
+            hash_files: paths("CHELA_BLOCKLIST_HASHES"),
+            lists: Arc::new(RwLock::new(Lists::default())),
+        };
+        screener.reload();
+        screener
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.domain_files.is_empty() || !self.hash_files.is_empty()
+    }
+
+    /// Returns a short reason if `url` matches a loaded blocklist.
+    pub fn check(&self, url: &Url) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+        let lists = self.lists.read().unwrap();
+
+        let mut suffix = host.as_str();
+        loop {
+            if lists.domains.contains(suffix) {
+                return Some(format!("domain '{suffix}' is on a blocklist"));
+            }
+            match suffix.split_once('.') {
+                Some((_, rest)) => suffix = rest,
+                None => break,
+            }
+        }
+
+        for expression in url_expressions(url) {
+            let hash = Sha256::digest(expression.as_bytes());
+            for len in &lists.prefix_lengths {
+                if lists.hash_prefixes.contains(&hash[..*len]) {
+                    return Some(format!("'{expression}' matches a blocklisted hash"));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Periodically checks the blocklist files' modification times and reloads them when
+    /// any of them changed.
+    pub fn spawn_refresh(&self, interval: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let screener = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let current = screener.modified_times();
+                if current != screener.lists.read().unwrap().modified {
+                    screener.reload();
+                }
+            }
+        });
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        self.domain_files
+            .iter()
+            .chain(self.hash_files.iter())
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn reload(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut lists = Lists {
+            modified: self.modified_times(),
+            ..Default::default()
+        };
+
+        for path in &self.domain_files {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    for line in list_lines(&contents) {
+                        // Accept hosts-file style entries like `0.0.0.0 example.com`.
+                        if let Some(domain) = line.split_whitespace().last() {
+                            lists
+                                .domains
+                                .insert(domain.trim_end_matches('.').to_lowercase());
+                        }
+                    }
+                }
+                Err(err) => warn!("Failed to read blocklist {}: {}", path.display(), err),
+            }
+        }
+
+        for path in &self.hash_files {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    for line in list_lines(&contents) {
+                        match hex::decode(line) {
+                            Ok(prefix) if (4..=32).contains(&prefix.len()) => {
+                                lists.hash_prefixes.insert(prefix);
+                            }
+                            _ => warn!("Ignoring invalid hash prefix '{}'", line),
+                        }
+                    }
+                }
+                Err(err) => warn!("Failed to read blocklist {}: {}", path.display(), err),
+            }
+        }
+
+        let mut lengths: Vec<usize> = lists.hash_prefixes.iter().map(Vec::len).collect();
+        lengths.sort_unstable();
+        lengths.dedup();
+        lists.prefix_lengths = lengths;
+
+        info!(
+            "Loaded {} blocklisted domains and {} hash prefixes",
+            lists.domains.len(),
+            lists.hash_prefixes.len()
+        );
+        *self.lists.write().unwrap() = lists;
+    }
+}
+
+fn list_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+/// Builds the host-suffix/path-prefix expressions that the Safe Browsing hash lists are
+/// computed over.
+fn url_expressions(url: &Url) -> Vec<String> {
+    let Some(host) = url.host_str() else {
+        return vec![];
+    };
+    let host = host.trim_end_matches('.').to_lowercase();
+
+    let mut hosts = vec![host.clone()];
+    if host.parse::<IpAddr>().is_err() && !host.starts_with('[') {
+        let labels: Vec<&str> = host.split('.').collect();
+        let start = labels.len().saturating_sub(5).max(1);
+        for i in start..labels.len().saturating_sub(1) {
+            hosts.push(labels[i..].join("."));
+        }
+    }
+
+    let path = url.path();
+    let mut paths = vec![];
+    if let Some(query) = url.query() {
+        paths.push(format!("{path}?{query}"));
+    }
+    paths.push(path.to_string());
+    let segments: Vec<&str> = path
+        .trim_start_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let mut prefix = String::from("/");
+    paths.push(prefix.clone());
+    for segment in segments.iter().take(3) {
+        prefix.push_str(segment);
+        prefix.push('/');
+        paths.push(prefix.clone());
+    }
+    paths.dedup();
+
+    let mut expressions = vec![];
+    for h in &hosts {
+        for p in &paths {
+            let expression = format!("{h}{p}");
+            if !expressions.contains(&expression) {
+                expressions.push(expression);
+            }
+        }
+    }
+    expressions
+}
@@ -0,0 +1,573 @@
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone};
+use maxminddb::geoip2;
+use tracing::{info, Instrument};
+
+use crate::error::Result;
+use crate::proxy::ClientInfo;
+use crate::telemetry;
+use crate::RuleRow;
+use crate::ServerState;
+use crate::UrlRow;
+
+const DEVICES: &[&str] = &["mobile", "tablet", "desktop"];
+const SYSTEMS: &[&str] = &["ios", "android", "windows", "macos", "linux", "chromeos"];
+const DAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// What a targeting rule looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Country,
+    Day,
+    Device,
+    Language,
+    Os,
+    Time,
+}
+
+impl Field {
+    pub const ALL: [Field; 6] = [
+        Field::Device,
+        Field::Os,
+        Field::Language,
+        Field::Country,
+        Field::Day,
+        Field::Time,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Country => "country",
+            Field::Day => "day",
+            Field::Device => "device",
+            Field::Language => "language",
+            Field::Os => "os",
+            Field::Time => "time",
+        }
+    }
+
+    /// Turns the comma-separated values of a new rule into the form they are matched in:
+    /// upper case ISO 3166 codes for countries, single days for day ranges, `HH:MM-HH:MM`
+    /// for time ranges and lower case for everything else.
+    pub fn parse_values(self, values: &str) -> std::result::Result<Vec<String>, String> {
+        let mut parsed = Vec::new();
+        for value in values.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            for value in self.parse_value(value)? {
+                if !parsed.contains(&value) {
+                    parsed.push(value);
+                }
+            }
+        }
+        if parsed.is_empty() {
+            return Err(format!("A {self} rule needs at least one value"));
+        }
+        Ok(parsed)
+    }
+
+    fn parse_value(self, value: &str) -> std::result::Result<Vec<String>, String> {
+        let lower = value.to_lowercase();
+        match self {
+            Field::Country => {
+                if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) {
+                    Ok(vec![value.to_uppercase()])
+                } else {
+                    Err(format!(
+                        "'{value}' is not a two-letter country code like US or DE"
+                    ))
+                }
+            }
+            Field::Device if DEVICES.contains(&lower.as_str()) => Ok(vec![lower]),
+            Field::Device => Err(format!(
+                "Unknown device '{value}', use one of {}",
+                DEVICES.join(", ")
+            )),
+            Field::Os if SYSTEMS.contains(&lower.as_str()) => Ok(vec![lower]),
+            Field::Os => Err(format!(
+                "Unknown operating system '{value}', use one of {}",
+                SYSTEMS.join(", ")
+            )),
+            Field::Language => {
+                let valid = lower.len() <= 35
+                    && lower.split('-').all(|part| {
+                        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())
+                    });
+                if valid {
+                    Ok(vec![lower])
+                } else {
+                    Err(format!("'{value}' is not a language tag like en or pt-BR"))
+                }
+            }
+            Field::Day => {
+                let day = |name: &str| DAYS.iter().position(|day| *day == name);
+                let (first, last) = match lower.split_once('-') {
+                    Some((first, last)) => (day(first.trim()), day(last.trim())),
+                    None => (day(&lower), day(&lower)),
+                };
+                let (Some(first), Some(last)) = (first, last) else {
+                    return Err(format!(
+                        "Unknown day '{value}', use mon to sun or a range like mon-fri"
+                    ));
+                };
+                // Ranges can wrap around the end of the week, e.g. `fri-mon`.
+                let count = (last + DAYS.len() - first) % DAYS.len() + 1;
+                Ok((first..first + count)
+                    .map(|day| DAYS[day % DAYS.len()].to_string())
+                    .collect())
+            }
+            Field::Time => {
+                let range = lower.split_once('-').and_then(|(start, end)| {
+                    let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+                    let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+                    Some((start, end)).filter(|(start, end)| start != end)
+                });
+                match range {
+                    Some((start, end)) => Ok(vec![format!(
+                        "{}-{}",
+                        start.format("%H:%M"),
+                        end.format("%H:%M")
+                    )]),
+                    None => Err(format!("'{value}' is not a time range like 09:00-17:00")),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Field::ALL
+            .into_iter()
+            .find(|field| field.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown targeting field '{s}', use one of {}",
+                    Field::ALL.map(Field::name).join(", ")
+                )
+            })
+    }
+}
+
+/// Countries of visitors, looked up in a local MaxMind GeoIP2 or GeoLite2 database.
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    reader: Option<Arc<maxminddb::Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    /// Reads the database at `CHELA_GEOIP_DATABASE`, if it is set. Without one, country rules
+    /// can't be added and never match.
+    pub fn from_env() -> eyre::Result<Self> {
+        let Ok(path) = env::var("CHELA_GEOIP_DATABASE") else {
+            return Ok(Self::default());
+        };
+        let reader = maxminddb::Reader::open_readfile(&path)
+            .map_err(|err| eyre::eyre!("CHELA_GEOIP_DATABASE '{}' can't be read: {}", path, err))?;
+        info!(
+            "Loaded GeoIP database {} from {}",
+            reader.metadata.database_type, path
+        );
+        Ok(Self {
+            reader: Some(Arc::new(reader)),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    fn country(&self, ip: &str) -> Option<String> {
+        let ip: IpAddr = ip.parse().ok()?;
+        let record: geoip2::Country = self.reader.as_ref()?.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+}
+
+/// What the rules of a link can tell about the person following it, and when they do.
+#[derive(Debug, Default)]
+pub struct Visitor {
+    country: Option<String>,
+    device: Option<&'static str>,
+    os: Option<&'static str>,
+    languages: Vec<String>,
+    day: &'static str,
+    time: NaiveTime,
+}
+
+impl Visitor {
+    /// `now` is in the time zone that day and time rules are written in.
+    pub fn new(
+        headers: &HeaderMap,
+        client: &ClientInfo,
+        geoip: &GeoIp,
+        now: DateTime<impl TimeZone>,
+    ) -> Self {
+        let mut visitor = Self {
+            country: geoip.country(&client.ip),
+            languages: preferred_languages(headers),
+            day: DAYS[now.weekday().num_days_from_monday() as usize],
+            time: now.time(),
+            ..Self::default()
+        };
+
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|it| it.to_str().ok())
+            .unwrap_or_default();
+        if let Some(agent) = woothee::parser::Parser::new().parse(user_agent) {
+            visitor.os = match agent.os {
+                "iPhone" | "iPad" | "iPod" => Some("ios"),
+                "Android" => Some("android"),
+                "Mac OSX" => Some("macos"),
+                "ChromeOS" => Some("chromeos"),
+                "Linux" => Some("linux"),
+                os if os.starts_with("Windows") && agent.category == "pc" => Some("windows"),
+                _ => None,
+            };
+            // Android tablets leave `Mobile` out of their user agents.
+            visitor.device = match agent.category {
+                "pc" => Some("desktop"),
+                "smartphone" if agent.os == "iPad" => Some("tablet"),
+                "smartphone" if agent.os == "Android" && !user_agent.contains("Mobile") => {
+                    Some("tablet")
+                }
+                "smartphone" | "mobilephone" => Some("mobile"),
+                _ => None,
+            };
+        }
+        visitor
+    }
+
+    fn matches(&self, rule: &RuleRow) -> bool {
+        let Ok(field) = rule.field.parse::<Field>() else {
+            return false;
+        };
+        let value = match field {
+            Field::Country => self.country.as_deref(),
+            Field::Day => Some(self.day),
+            Field::Device => self.device,
+            Field::Os => self.os,
+            Field::Language => {
+                // `de` also matches `de-AT`, but `de-AT` doesn't match `de`.
+                return self.languages.iter().any(|language| {
+                    rule.matches.iter().any(|expected| {
+                        language == expected
+                            || language
+                                .strip_prefix(expected.as_str())
+                                .is_some_and(|rest| rest.starts_with('-'))
+                    })
+                });
+            }
+            Field::Time => {
+                return rule
+                    .matches
+                    .iter()
+                    .any(|range| in_time_range(range, self.time))
+            }
+        };
+        let Some(value) = value else {
+            return false;
+        };
+
+        rule.matches.iter().any(|expected| value == expected)
+    }
+}
+
+/// Whether `time` is within a `HH:MM-HH:MM` range, which includes its start but not its end.
+/// Ranges whose end is before their start go past midnight.
+fn in_time_range(range: &str, time: NaiveTime) -> bool {
+    let Some((start, end)) = range.split_once('-').and_then(|(start, end)| {
+        Some((
+            NaiveTime::parse_from_str(start, "%H:%M").ok()?,
+            NaiveTime::parse_from_str(end, "%H:%M").ok()?,
+        ))
+    }) else {
+        return false;
+    };
+    if start < end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+/// The languages in `Accept-Language` from the highest weight to the lowest, keeping the order
+/// of the header for equal weights.
+fn preferred_languages(headers: &HeaderMap) -> Vec<String> {
+    let Some(accept) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|it| it.to_str().ok())
+    else {
+        return Vec::new();
+    };
+    let mut languages: Vec<(&str, f32)> = Vec::new();
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let tag = parts.next().unwrap_or_default();
+        let weight = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        if tag.is_empty() || tag == "*" || weight <= 0.0 {
+            continue;
+        }
+        languages.push((tag, weight));
+    }
+    languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    languages
+        .into_iter()
+        .map(|(tag, _)| tag.to_lowercase())
+        .collect()
+}
+
+/// The targeting rules of a link, in the order they are tried.
+pub async fn list(state: &ServerState, link: &UrlRow) -> Result<Vec<RuleRow>> {
+    Ok(
+        sqlx::query_as("SELECT * FROM chela.rules WHERE link = $1 ORDER BY position, id")
+            .bind(link.index)
+            .fetch_all(&state.db_pool)
+            .instrument(telemetry::db_span("SELECT", "chela.rules"))
+            .await?,
+    )
+}
+
+/// The first rule that matches the visitor, if any.
+pub fn first_match<'a>(rules: &'a [RuleRow], visitor: &Visitor) -> Option<&'a RuleRow> {
+    rules.iter().find(|rule| visitor.matches(rule))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap};
+    use chrono::{NaiveTime, TimeZone, Utc};
+
+    use super::{in_time_range, preferred_languages, Field, GeoIp, Visitor};
+    use crate::proxy::ClientInfo;
+    use crate::RuleRow;
+
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M").unwrap()
+    }
+
+    fn visitor(user_agent: &str, accept_language: &str) -> Visitor {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+        headers.insert(header::ACCEPT_LANGUAGE, accept_language.parse().unwrap());
+        let client = ClientInfo {
+            ip: "203.0.113.7".to_string(),
+            scheme: "https".to_string(),
+            host: "a.com".to_string(),
+        };
+        let now = chrono_tz::America::New_York
+            .with_ymd_and_hms(2024, 6, 2, 23, 30, 0)
+            .unwrap();
+        Visitor::new(&headers, &client, &GeoIp::default(), now)
+    }
+
+    fn rule(field: Field, matches: &[&str]) -> RuleRow {
+        RuleRow {
+            id: 1,
+            link: 1,
+            position: 1,
+            field: field.to_string(),
+            matches: matches.iter().map(|m| m.to_string()).collect(),
+            url: "https://example.com/".to_string(),
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(
+            Field::Country.parse_values("us, De"),
+            Ok(vec!["US".to_string(), "DE".to_string()])
+        );
+        assert!(Field::Country.parse_values("USA").is_err());
+        assert_eq!(
+            Field::Device.parse_values("Mobile,tablet"),
+            Ok(vec!["mobile".to_string(), "tablet".to_string()])
+        );
+        assert!(Field::Device.parse_values("watch").is_err());
+        assert_eq!(Field::Os.parse_values("iOS"), Ok(vec!["ios".to_string()]));
+        assert_eq!(
+            Field::Language.parse_values("pt-BR, en"),
+            Ok(vec!["pt-br".to_string(), "en".to_string()])
+        );
+        assert!(Field::Language.parse_values("en_US").is_err());
+        assert!(Field::Os.parse_values(" , ").is_err());
+    }
+
+    #[test]
+    fn expands_day_ranges() {
+        assert_eq!(
+            Field::Day.parse_values("mon-wed, sat"),
+            Ok(["mon", "tue", "wed", "sat"].map(String::from).to_vec())
+        );
+        assert_eq!(
+            Field::Day.parse_values("fri-mon"),
+            Ok(["fri", "sat", "sun", "mon"].map(String::from).to_vec())
+        );
+        assert!(Field::Day.parse_values("monday").is_err());
+    }
+
+    #[test]
+    fn normalizes_time_ranges() {
+        assert_eq!(
+            Field::Time.parse_values("9:00-17:30, 22:00 - 06:00"),
+            Ok(vec!["09:00-17:30".to_string(), "22:00-06:00".to_string()])
+        );
+        assert!(Field::Time.parse_values("09:00-09:00").is_err());
+        assert!(Field::Time.parse_values("09:00").is_err());
+        assert!(Field::Time.parse_values("25:00-26:00").is_err());
+    }
+
+    #[test]
+    fn time_ranges_include_their_start_only() {
+        assert!(in_time_range("09:00-17:00", time("09:00")));
+        assert!(in_time_range("09:00-17:00", time("16:59")));
+        assert!(!in_time_range("09:00-17:00", time("17:00")));
+        assert!(!in_time_range("09:00-17:00", time("08:59")));
+    }
+
+    #[test]
+    fn time_ranges_can_wrap_past_midnight() {
+        assert!(in_time_range("22:00-06:00", time("23:30")));
+        assert!(in_time_range("22:00-06:00", time("00:00")));
+        assert!(in_time_range("22:00-06:00", time("05:59")));
+        assert!(!in_time_range("22:00-06:00", time("06:00")));
+        assert!(!in_time_range("22:00-06:00", time("12:00")));
+        assert!(!in_time_range("nonsense", time("12:00")));
+    }
+
+    #[test]
+    fn orders_languages_by_weight() {
+        let languages = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_LANGUAGE, value.parse().unwrap());
+            preferred_languages(&headers)
+        };
+        assert_eq!(languages("de-AT,de;q=0.9,en;q=0.8"), ["de-at", "de", "en"]);
+        assert_eq!(languages("en;q=0.5, fr;q=0.8"), ["fr", "en"]);
+        assert_eq!(languages("en;q=0.8, fr;q=0.8"), ["en", "fr"]);
+        assert_eq!(languages("*, nl;q=0.1"), ["nl"]);
+        assert!(languages("en;q=0").is_empty());
+        assert!(preferred_languages(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn matches_any_accepted_language() {
+        let swiss = visitor("", "fr-CH, de;q=0.9");
+        assert!(swiss.matches(&rule(Field::Language, &["de"])));
+        assert!(swiss.matches(&rule(Field::Language, &["fr"])));
+        assert!(!swiss.matches(&rule(Field::Language, &["de-at", "en"])));
+        assert!(!visitor("", "en;q=0, de").matches(&rule(Field::Language, &["en"])));
+    }
+
+    #[test]
+    fn recognizes_devices_and_systems() {
+        let cases = [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1",
+                Some("ios"),
+                Some("mobile"),
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1",
+                Some("ios"),
+                Some("tablet"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+                Some("android"),
+                Some("mobile"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SM-X700) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                Some("android"),
+                Some("tablet"),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                Some("windows"),
+                Some("desktop"),
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Safari/605.1.15",
+                Some("macos"),
+                Some("desktop"),
+            ),
+            (
+                "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                Some("chromeos"),
+                Some("desktop"),
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                Some("linux"),
+                Some("desktop"),
+            ),
+            ("curl/8.5.0", None, None),
+        ];
+        for (user_agent, os, device) in cases {
+            let visitor = visitor(user_agent, "en");
+            assert_eq!((visitor.os, visitor.device), (os, device), "{user_agent}");
+        }
+    }
+
+    #[test]
+    fn matches_rules() {
+        let android = visitor(
+            "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+            "de-AT,de;q=0.9",
+        );
+        // Sunday 23:30 in New York.
+        assert!(android.matches(&rule(Field::Day, &["sat", "sun"])));
+        assert!(!android.matches(&rule(Field::Day, &["mon"])));
+        assert!(android.matches(&rule(Field::Time, &["09:00-17:00", "22:00-06:00"])));
+        assert!(!android.matches(&rule(Field::Time, &["09:00-17:00"])));
+        assert!(android.matches(&rule(Field::Language, &["de"])));
+        assert!(!android.matches(&rule(Field::Language, &["de-ch", "d"])));
+        assert!(android.matches(&rule(Field::Device, &["mobile"])));
+        assert!(!android.matches(&rule(Field::Country, &["US"])));
+
+        let german = visitor("curl/8.5.0", "de");
+        assert!(!german.matches(&rule(Field::Language, &["de-at"])));
+    }
+
+    #[test]
+    fn uses_the_day_and_time_of_the_time_zone() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "curl/8.5.0".parse().unwrap());
+        let client = ClientInfo {
+            ip: String::new(),
+            scheme: "http".to_string(),
+            host: "a.com".to_string(),
+        };
+        let now = Utc.with_ymd_and_hms(2024, 6, 3, 3, 30, 0).unwrap();
+        let visitor = Visitor::new(&headers, &client, &GeoIp::default(), now);
+        assert_eq!((visitor.day, visitor.time), ("mon", time("03:30")));
+        let visitor = Visitor::new(
+            &headers,
+            &client,
+            &GeoIp::default(),
+            now.with_timezone(&chrono_tz::America::New_York),
+        );
+        assert_eq!((visitor.day, visitor.time), ("sun", time("23:30")));
+    }
+}
@@ -0,0 +1,181 @@
+use std::env;
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Builds an OTLP/gRPC tracer if `CHELA_OTLP_ENDPOINT` is set, e.g. `http://localhost:4317`.
+pub fn tracer_from_env() -> Result<Option<trace::Tracer>, TraceError> {
+    let Ok(endpoint) = env::var("CHELA_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let service_name = env::var("CHELA_OTLP_SERVICE_NAME").unwrap_or("chela".to_string());
+    tracer(endpoint, service_name).map(Some)
+}
+
+fn tracer(endpoint: String, service_name: String) -> Result<trace::Tracer, TraceError> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name,
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+}
+
+/// Flushes spans that haven't been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|it| it.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Continues the trace from an incoming W3C `traceparent` header, if there is one.
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    span.set_parent(context);
+}
+
+/// A client span for a single database query, e.g. `db_span("SELECT", "chela.urls")`.
+pub fn db_span(operation: &'static str, table: &'static str) -> Span {
+    tracing::info_span!(
+        "db.query",
+        otel.name = %format!("{operation} {table}"),
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.operation = operation,
+        db.sql.table = table,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::HeaderMap;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response, Status};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{db_span, set_parent_from_headers, shutdown, tracer};
+
+    /// Stands in for an OTLP collector and keeps everything that is exported to it.
+    #[derive(Clone, Default)]
+    struct Collector {
+        exports: Arc<Mutex<Vec<ExportTraceServiceRequest>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            self.exports.lock().unwrap().push(request.into_inner());
+            Ok(Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }))
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_spans_to_an_otlp_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Collector::default();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let tracer = tracer(endpoint, "chela-test".to_string()).unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request");
+            set_parent_from_headers(&request, &headers);
+            request.in_scope(|| db_span("SELECT", "chela.urls").in_scope(|| {}));
+        });
+        // Shutting down blocks until the batch has been exported.
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let exports = collector.exports.lock().unwrap();
+        let resource_spans: Vec<_> = exports
+            .iter()
+            .flat_map(|export| &export.resource_spans)
+            .collect();
+        let service_name = resource_spans
+            .iter()
+            .filter_map(|spans| spans.resource.as_ref())
+            .flat_map(|resource| &resource.attributes)
+            .find(|attribute| attribute.key == "service.name")
+            .and_then(|attribute| attribute.value.as_ref()?.value.as_ref());
+        assert_eq!(
+            service_name,
+            Some(&Value::StringValue("chela-test".to_string()))
+        );
+
+        let spans: Vec<_> = resource_spans
+            .iter()
+            .flat_map(|spans| &spans.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .collect();
+        let request = spans.iter().find(|span| span.name == "request").unwrap();
+        let query = spans
+            .iter()
+            .find(|span| span.name == "SELECT chela.urls")
+            .unwrap();
+        assert_eq!(hex(&request.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&request.parent_span_id), "00f067aa0ba902b7");
+        assert_eq!(query.trace_id, request.trace_id);
+        assert_eq!(query.parent_span_id, request.span_id);
+        assert_eq!(query.kind, SpanKind::Client as i32);
+    }
+}
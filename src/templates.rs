@@ -0,0 +1,162 @@
+use askama::Template;
+use axum::http::header;
+use axum::response::{Html, IntoResponse};
+
+use crate::error::Result;
+use crate::LinkStats;
+use crate::RuleRow;
+use crate::TrackingRow;
+use crate::UrlRow;
+
+/// Renders a page, turning template failures into a `500` like any other internal error.
+pub fn render(template: &impl Template) -> Result<Html<String>> {
+    template
+        .render()
+        .map(Html)
+        .map_err(|err| eyre::eyre!("failed to render template: {}", err).into())
+}
+
+/// The dashboard path for a link, e.g. `/tracking/abc`.
+pub fn stats_path(id: &str) -> String {
+    format!(
+        "/tracking/{}",
+        percent_encoding::utf8_percent_encode(id, percent_encoding::NON_ALPHANUMERIC)
+    )
+}
+
+/// The stylesheet shared by every page, served from `/static/chela.css`.
+pub async fn stylesheet() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        include_str!("../static/chela.css"),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+pub struct Index<'a> {
+    pub host: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "create.html")]
+pub struct Create<'a> {
+    pub host: &'a str,
+    pub csrf_token: &'a str,
+    pub domains: Vec<String>,
+    pub strategies: Vec<String>,
+    pub default_strategy: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "link.html")]
+pub struct Link<'a> {
+    pub host: &'a str,
+    pub id: &'a str,
+    pub short_url: String,
+    pub url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "preview.html")]
+pub struct Preview<'a> {
+    pub host: &'a str,
+    pub id: &'a str,
+    pub short_url: &'a str,
+    pub url_before: &'a str,
+    pub url_registrable: &'a str,
+    pub url_after: &'a str,
+    pub warnings: &'a [String],
+    pub title: Option<&'a str>,
+    pub created_at: Option<String>,
+    pub clicks: i64,
+}
+
+#[derive(Template)]
+#[template(path = "redirect.html")]
+pub struct Redirect<'a> {
+    pub url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "warning.html")]
+pub struct Warning<'a> {
+    pub host: &'a str,
+    pub url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage<'a> {
+    pub status: u16,
+    pub error: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "tracking.html")]
+pub struct Tracking<'a> {
+    pub host: &'a str,
+    pub csrf_token: &'a str,
+    pub base_url: &'a str,
+    pub domains: Vec<String>,
+    pub strategies: Vec<String>,
+    pub default_strategy: &'a str,
+    pub links: &'a [LinkStats],
+}
+
+#[derive(Template)]
+#[template(path = "tracking_id.html")]
+pub struct TrackingId<'a> {
+    pub host: &'a str,
+    pub csrf_token: &'a str,
+    pub base_url: &'a str,
+    pub link: &'a UrlRow,
+    pub visits: &'a [TrackingRow],
+    pub by_alias: Vec<(String, usize)>,
+    pub by_variant: Vec<VariantStats>,
+    pub rules: &'a [RuleRow],
+    pub targeting_fields: Vec<String>,
+    pub has_geoip: bool,
+    pub active_from: String,
+    pub active_until: String,
+    pub time_zone: &'a str,
+    pub by_ip: Vec<(String, u32)>,
+    pub by_referrer: Vec<(String, u32)>,
+    pub by_user_agent: Vec<(String, u32)>,
+}
+
+/// How one destination of a split link is doing.
+pub struct VariantStats {
+    pub name: String,
+    /// `None` for destinations that have been removed.
+    pub url: Option<String>,
+    pub weight: i32,
+    /// The percentage of new visitors that are sent to it.
+    pub share: i32,
+    pub clicks: usize,
+}
+
+/// Shown instead of the redirect while a link isn't active yet.
+#[derive(Template)]
+#[template(path = "pending.html")]
+pub struct Pending<'a> {
+    pub host: &'a str,
+    pub available_from: &'a str,
+}
+
+/// The Open Graph and Twitter card that unfurl bots see for links with card overrides.
+#[derive(Template)]
+#[template(path = "card.html")]
+pub struct Card<'a> {
+    pub host: &'a str,
+    pub short_url: &'a str,
+    pub url: &'a str,
+    pub title: &'a str,
+    pub description: Option<&'a str>,
+    pub image: Option<&'a str>,
+    pub oembed_url: &'a str,
+}
@@ -0,0 +1,219 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::Host;
+use axum::http::Uri;
+use axum::response::{IntoResponse, Redirect};
+use axum::routing::get;
+use axum::{Extension, Router};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+use tracing::{debug, info, warn};
+
+use crate::health;
+use crate::spawn_connection;
+use crate::ServerState;
+
+/// How long a client gets to finish the TLS handshake before the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub port: u16,
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    /// Returns `None` unless both `CHELA_TLS_CERT` and `CHELA_TLS_KEY` are set.
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let (Ok(cert_path), Ok(key_path)) = (env::var("CHELA_TLS_CERT"), env::var("CHELA_TLS_KEY"))
+        else {
+            return Ok(None);
+        };
+        let port = match env::var("CHELA_TLS_PORT") {
+            Ok(port) => port.parse()?,
+            Err(_) => 3443,
+        };
+        let reload_interval = match env::var("CHELA_TLS_RELOAD") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => Duration::from_secs(60),
+        };
+
+        Ok(Some(Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            port,
+            reload_interval,
+        }))
+    }
+}
+
+/// Hands out the most recently loaded certificate, so it can be swapped without restarting.
+struct ReloadingResolver {
+    config: TlsConfig,
+    key: RwLock<(Arc<CertifiedKey>, Vec<Option<SystemTime>>)>,
+}
+
+impl ReloadingResolver {
+    fn new(config: TlsConfig) -> eyre::Result<Self> {
+        let modified = modified_times(&config);
+        let key = load_key(&config)?;
+        Ok(Self {
+            config,
+            key: RwLock::new((key, modified)),
+        })
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = modified_times(&self.config);
+        if modified == self.key.read().unwrap().1 {
+            return;
+        }
+        match load_key(&self.config) {
+            Ok(key) => {
+                *self.key.write().unwrap() = (key, modified);
+                info!(
+                    "Reloaded TLS certificate {}",
+                    self.config.cert_path.display()
+                );
+            }
+            Err(err) => warn!("Failed to reload TLS certificate: {}", err),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().0.clone())
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [&config.cert_path, &config.key_path]
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn load_key(config: &TlsConfig) -> eyre::Result<Arc<CertifiedKey>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert_path)?))?;
+    if certs.is_empty() {
+        return Err(eyre::eyre!(
+            "no certificates found in {}",
+            config.cert_path.display()
+        ));
+    }
+
+    let mut key = None;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(&config.key_path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => {
+                key = Some(der);
+                break;
+            }
+            _ => continue,
+        }
+    }
+    let key =
+        key.ok_or_else(|| eyre::eyre!("no private key found in {}", config.key_path.display()))?;
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))?;
+
+    Ok(Arc::new(CertifiedKey::new(
+        certs.into_iter().map(rustls::Certificate).collect(),
+        key,
+    )))
+}
+
+/// Serves `router` over HTTPS on the TLS port, and redirects plain HTTP on `http_port` to it.
+pub async fn serve(
+    router: Router,
+    state: ServerState,
+    address: &str,
+    http_port: u16,
+    config: TlsConfig,
+) -> eyre::Result<()> {
+    let resolver = Arc::new(ReloadingResolver::new(config.clone())?);
+    let reloader = resolver.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(reloader.config.reload_interval);
+        loop {
+            ticker.tick().await;
+            reloader.reload_if_changed();
+        }
+    });
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let tls_port = config.port;
+    // Health checks stay on plain HTTP so `chela healthcheck` doesn't need TLS.
+    let redirect_router = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .fallback(move |host: Host, uri: Uri| async move { redirect_to_https(host, uri, tls_port) })
+        .layer(Extension(state));
+    let redirect_listener = tokio::net::TcpListener::bind(format!("{address}:{http_port}")).await?;
+    info!("Redirecting HTTP at {}:{} to HTTPS", address, http_port);
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(redirect_listener, redirect_router).await {
+            warn!("HTTP redirect listener failed: {}", err);
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind(format!("{address}:{tls_port}")).await?;
+    info!("Listening with TLS at {}:{}", address, tls_port);
+    let mut service = router.into_make_service_with_connect_info::<SocketAddr>();
+    loop {
+        let (socket, remote_addr) = match listener.accept().await {
+            Ok(it) => it,
+            Err(err) => {
+                warn!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let tower_service = match service.call(remote_addr).await {
+            Ok(value) => value,
+            Err(err) => match err {},
+        };
+
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => spawn_connection(stream, tower_service),
+                Ok(Err(err)) => warn!("TLS handshake with {} failed: {}", remote_addr, err),
+                Err(_) => debug!("TLS handshake with {} timed out", remote_addr),
+            }
+        });
+    }
+}
+
+fn redirect_to_https(Host(host): Host, uri: Uri, tls_port: u16) -> impl IntoResponse {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => host,
+    };
+    let authority = if tls_port == 443 {
+        host
+    } else {
+        format!("{host}:{tls_port}")
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+
+    Redirect::permanent(&format!("https://{authority}{path}"))
+}
@@ -0,0 +1,224 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use rand::Rng;
+use tracing::Instrument;
+
+use crate::error::Result;
+use crate::proxy::ClientInfo;
+use crate::telemetry;
+use crate::ServerState;
+use crate::UrlRow;
+use crate::VariantRow;
+
+/// The link's own destination. Extra destinations are named `B` to `Z`.
+pub const PRIMARY: &str = "A";
+
+/// The most weight a single destination can have.
+pub const MAX_WEIGHT: i32 = 1000;
+
+/// How long a visitor keeps being sent to the destination they were first assigned, in
+/// seconds.
+const COOKIE_MAX_AGE: u32 = 60 * 60 * 24 * 30;
+
+/// The extra destinations of a link, in the order they were named.
+pub async fn list(state: &ServerState, link: &UrlRow) -> Result<Vec<VariantRow>> {
+    Ok(
+        sqlx::query_as("SELECT * FROM chela.variants WHERE link = $1 ORDER BY name")
+            .bind(link.index)
+            .fetch_all(&state.db_pool)
+            .instrument(telemetry::db_span("SELECT", "chela.variants"))
+            .await?,
+    )
+}
+
+/// The first name for another destination of a link that is free. Names of removed
+/// destinations that were visited aren't reused, so that their clicks stay apart.
+pub async fn next_name(state: &ServerState, link: &UrlRow) -> Result<Option<String>> {
+    let used: Vec<(String,)> = sqlx::query_as(
+        "
+SELECT name FROM chela.variants WHERE link = $1
+UNION
+SELECT tracking.variant
+FROM chela.tracking
+JOIN chela.aliases ON aliases.domain = tracking.domain AND aliases.id = tracking.id
+WHERE aliases.link = $1 AND tracking.variant IS NOT NULL
+        ",
+    )
+    .bind(link.index)
+    .fetch_all(&state.db_pool)
+    .instrument(telemetry::db_span("SELECT", "chela.variants"))
+    .await?;
+    Ok(('B'..='Z')
+        .map(String::from)
+        .find(|name| used.iter().all(|(used,)| used != name)))
+}
+
+/// Where a visit to a split link goes.
+pub struct Choice {
+    pub name: String,
+    pub url: String,
+    /// Set when the visitor had no (valid) assignment yet.
+    pub cookie: Option<HeaderValue>,
+}
+
+/// Picks one of the link's destinations, with `A` being the link's own URL. Visitors who
+/// were assigned one before, and still have its cookie, get the same one again as long as
+/// it still exists.
+pub fn choose(
+    headers: &HeaderMap,
+    client: &ClientInfo,
+    link: &UrlRow,
+    variants: &[VariantRow],
+) -> Result<Choice> {
+    let mut options = vec![(PRIMARY, link.url.as_str(), link.weight)];
+    options.extend(
+        variants
+            .iter()
+            .map(|variant| (variant.name.as_str(), variant.url.as_str(), variant.weight)),
+    );
+
+    let cookie_name = cookie_name(link);
+    let assigned = cookie_value(headers, &cookie_name)
+        .and_then(|name| options.iter().find(|(option, _, _)| *option == name));
+    if let Some((name, url, _)) = assigned {
+        return Ok(Choice {
+            name: name.to_string(),
+            url: url.to_string(),
+            cookie: None,
+        });
+    }
+
+    let total: i32 = options.iter().map(|(_, _, weight)| weight).sum();
+    let mut pick = rand::thread_rng().gen_range(0..total);
+    let (name, url, _) = options
+        .iter()
+        .find(|(_, _, weight)| {
+            if pick < *weight {
+                return true;
+            }
+            pick -= weight;
+            false
+        })
+        .unwrap_or(&options[0]);
+
+    let cookie = format!(
+        "{cookie_name}={name}; Path=/; Max-Age={COOKIE_MAX_AGE}; HttpOnly; SameSite=Lax{}",
+        if client.scheme == "https" {
+            "; Secure"
+        } else {
+            ""
+        }
+    );
+    let cookie = cookie
+        .parse()
+        .map_err(|err| eyre::eyre!("invalid variant cookie: {}", err))?;
+    Ok(Choice {
+        name: name.to_string(),
+        url: url.to_string(),
+        cookie: Some(cookie),
+    })
+}
+
+/// One cookie per link, shared by all of its aliases.
+fn cookie_name(link: &UrlRow) -> String {
+    format!("chela_ab_{}", link.index)
+}
+
+fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap};
+
+    use super::{choose, PRIMARY};
+    use crate::proxy::ClientInfo;
+    use crate::UrlRow;
+    use crate::VariantRow;
+
+    fn client(scheme: &str) -> ClientInfo {
+        ClientInfo {
+            ip: "203.0.113.7".to_string(),
+            scheme: scheme.to_string(),
+            host: "a.com".to_string(),
+        }
+    }
+
+    fn link() -> UrlRow {
+        UrlRow {
+            index: 7,
+            url: "https://example.com/a".to_string(),
+            weight: 1,
+            ..UrlRow::default()
+        }
+    }
+
+    fn variant(name: &str, weight: i32) -> VariantRow {
+        VariantRow {
+            link: 7,
+            name: name.to_string(),
+            url: format!("https://example.com/{}", name.to_lowercase()),
+            weight,
+            created_at: None,
+        }
+    }
+
+    fn cookie(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn assigns_new_visitors_and_sets_a_cookie() {
+        let choice = choose(&HeaderMap::new(), &client("https"), &link(), &[]).unwrap();
+        assert_eq!(choice.name, PRIMARY);
+        assert_eq!(choice.url, "https://example.com/a");
+        assert_eq!(
+            choice.cookie.unwrap(),
+            "chela_ab_7=A; Path=/; Max-Age=2592000; HttpOnly; SameSite=Lax; Secure"
+        );
+
+        let choice = choose(&HeaderMap::new(), &client("http"), &link(), &[]).unwrap();
+        assert!(!choice.cookie.unwrap().to_str().unwrap().contains("Secure"));
+    }
+
+    #[test]
+    fn keeps_assigned_visitors_on_their_destination() {
+        let variants = [variant("B", 1), variant("C", 1)];
+        let headers = cookie("other=1; chela_ab_7=C; chela_ab_8=B");
+        for _ in 0..20 {
+            let choice = choose(&headers, &client("https"), &link(), &variants).unwrap();
+            assert_eq!(choice.name, "C");
+            assert_eq!(choice.url, "https://example.com/c");
+            assert!(choice.cookie.is_none());
+        }
+    }
+
+    #[test]
+    fn reassigns_visitors_whose_destination_was_removed() {
+        let headers = cookie("chela_ab_7=D");
+        let choice = choose(&headers, &client("https"), &link(), &[variant("B", 1)]).unwrap();
+        assert!(["A", "B"].contains(&choice.name.as_str()));
+        assert!(choice.cookie.is_some());
+    }
+
+    #[test]
+    fn splits_visitors_by_weight() {
+        let variants = [variant("B", 3)];
+        let mut counts = [0; 2];
+        for _ in 0..2000 {
+            let choice = choose(&HeaderMap::new(), &client("https"), &link(), &variants).unwrap();
+            counts[usize::from(choice.name == "B")] += 1;
+        }
+        // B should get about three quarters, i.e. 1500.
+        assert!((1350..1650).contains(&counts[1]), "{counts:?}");
+    }
+}